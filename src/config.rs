@@ -1,18 +1,270 @@
 use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-use config::Config;
+use anyhow::Context;
+use config::{Config, FileFormat};
+use globset::GlobBuilder;
+use log::warn;
+use regex::RegexBuilder;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::keyboard::Opcodes;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct I3WatcherEntry {
+    /// Key of the entry in the `entries` table
+    #[serde(skip)]
+    pub name: String,
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_string_or_seq_string")]
     pub include: Vec<String>,
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_string_or_seq_string")]
     pub exclude: Vec<String>,
+    /// File with one include pattern per line, appended to `include` at load time
+    pub include_file: Option<PathBuf>,
+    /// File with one exclude pattern per line, appended to `exclude` at load time
+    pub exclude_file: Option<PathBuf>,
     pub base_layer: Option<u8>,
     pub to_layer: Option<u8>,
+    /// Layer for the first focus of a newly created window, instead of `to_layer`
+    pub on_new_layer: Option<u8>,
+    /// Turns the mouse jiggler on or off when a matching window is focused
+    pub enable_jiggler: Option<bool>,
+    /// Jiggler state to go back to once focus leaves this entry
+    pub base_jiggler: Option<bool>,
+    /// Marks windows that play media, which keep the jiggler on with `jiggler_on_match`
+    #[serde(default)]
+    pub media: bool,
+    #[serde(default)]
+    pub trigger: Trigger,
+    #[serde(default)]
+    pub match_mode: MatchMode,
+    /// Only match floating or only tiled windows
+    pub window_mode: Option<WindowMode>,
+    /// Only match fullscreen windows when true, or only windows that aren't when false
+    pub fullscreen: Option<bool>,
+    /// Only match windows on the workspace with this name
+    pub workspace: Option<String>,
+    #[serde(default)]
+    pub match_type: MatchType,
+    #[serde(default)]
+    pub match_field: MatchField,
+    /// Command to run when a window matches, with `{layer}` and `{window}` substituted
+    pub notify_template: Option<String>,
+    /// Don't react again when focus stays on windows of the same class, e.g. with titles that
+    /// keep changing to show progress
+    #[serde(default)]
+    pub ignore_title_changes: bool,
+    #[serde(skip)]
+    patterns: OnceLock<CompiledPatterns>,
+}
+
+impl I3WatcherEntry {
+    fn compile(&self, patterns: &[String]) -> Vec<Result<Pattern, anyhow::Error>> {
+        patterns
+            .iter()
+            .map(|pattern| self.match_type.compile(pattern))
+            .collect()
+    }
+
+    /// Compiles the include and exclude patterns up front, failing on the first invalid one
+    fn compile_patterns(&self) -> Result<(), anyhow::Error> {
+        let strict = |patterns| {
+            self.compile(patterns)
+                .into_iter()
+                .map(|pattern| pattern.map(Some))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let patterns = CompiledPatterns {
+            include: strict(&self.include)?,
+            exclude: strict(&self.exclude)?,
+        };
+        let _ = self.patterns.set(patterns);
+
+        Ok(())
+    }
+
+    /// The compiled patterns, compiling them on first use if `compile_patterns` wasn't called
+    fn patterns(&self) -> &CompiledPatterns {
+        self.patterns.get_or_init(|| {
+            let lossy = |patterns| self.compile(patterns).into_iter().map(Result::ok).collect();
+            CompiledPatterns {
+                include: lossy(&self.include),
+                exclude: lossy(&self.exclude),
+            }
+        })
+    }
+
+    /// Whether moving from `prev_target` to `target`, both matching this entry, is only title
+    /// churn to ignore
+    pub fn ignores_change(&self, prev_target: &FocusTarget, target: &FocusTarget) -> bool {
+        self.ignore_title_changes
+            && !target.new_window
+            && prev_target.class.is_some()
+            && prev_target.class == target.class
+    }
+
+    /// Layer to change to when `target` matches this entry
+    pub fn layer_for(&self, target: &FocusTarget) -> Option<u8> {
+        if target.new_window {
+            self.on_new_layer.or(self.to_layer)
+        } else {
+            self.to_layer
+        }
+    }
+
+    /// Arguments of the `notify_template` command for `target`, with the layer `layer_for` picks
+    /// for it. The template is split on whitespace before substituting, so a window title can't
+    /// inject extra arguments or shell syntax
+    pub fn notify_command(&self, target: &FocusTarget) -> Option<Vec<String>> {
+        let layer = self
+            .layer_for(target)
+            .map(|layer| layer.to_string())
+            .unwrap_or_default();
+
+        let command = self
+            .notify_template
+            .as_ref()?
+            .split_whitespace()
+            .map(|arg| {
+                arg.replace("{layer}", &layer)
+                    .replace("{window}", &target.name)
+            })
+            .collect::<Vec<_>>();
+
+        (!command.is_empty()).then_some(command)
+    }
+}
+
+/// How include and exclude patterns are compared against names
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchType {
+    /// The name contains the pattern, ignoring case
+    #[default]
+    Substring,
+    /// The pattern is a case insensitive regex found somewhere in the name
+    Regex,
+    /// The whole name matches the pattern as a case insensitive glob, like `firefox*`
+    Glob,
+}
+
+impl MatchType {
+    /// Compiles `pattern` for this match type
+    fn compile(self, pattern: &str) -> Result<Pattern, anyhow::Error> {
+        Ok(match self {
+            Self::Substring => Pattern::Substring(pattern.to_lowercase()),
+            Self::Regex => {
+                Pattern::Regex(RegexBuilder::new(pattern).case_insensitive(true).build()?)
+            }
+            Self::Glob => Pattern::Glob(
+                GlobBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()?
+                    .compile_matcher(),
+            ),
+        })
+    }
+}
+
+/// An include or exclude pattern, prepared once so matching a name does no per-pattern work
+#[derive(Debug)]
+enum Pattern {
+    /// Already lowercased, to look for in the lowercased name
+    Substring(String),
+    Regex(regex::Regex),
+    Glob(globset::GlobMatcher),
+}
+
+impl Pattern {
+    fn is_match(&self, name: &str, lowercase_name: &str) -> bool {
+        match self {
+            Self::Substring(pattern) => lowercase_name.contains(pattern.as_str()),
+            Self::Regex(regex) => regex.is_match(name),
+            Self::Glob(glob) => glob.is_match(name),
+        }
+    }
+}
+
+/// Compiled `include` and `exclude` patterns of an entry. An invalid pattern is `None` and
+/// never matches
+#[derive(Debug, Default)]
+struct CompiledPatterns {
+    include: Vec<Option<Pattern>>,
+    exclude: Vec<Option<Pattern>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowMode {
+    Floating,
+    Tiled,
+}
+
+/// What is known about a focused window or workspace, for entries to match against
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FocusTarget {
+    pub name: String,
+    /// Whether the window is floating, when the source reports it
+    pub floating: Option<bool>,
+    /// Whether the window is fullscreen, when the source reports it
+    pub fullscreen: Option<bool>,
+    /// X11 `WM_CLASS` class of the window, when the source reports it
+    pub class: Option<String>,
+    /// X11 `WM_CLASS` instance of the window, when the source reports it
+    pub instance: Option<String>,
+    /// Whether this is the first focus of a window that was just created
+    pub new_window: bool,
+    /// Name of the workspace the window is on, when the source reports it
+    pub workspace: Option<String>,
+}
+
+/// Which part of a focused window an entry's patterns are matched against
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchField {
+    /// The window or workspace name, i.e. usually the title
+    #[default]
+    Name,
+    /// The window class, which unlike the title stays the same for an application
+    Class,
+    /// The window instance
+    Instance,
+}
+
+impl FocusTarget {
+    fn field(&self, field: MatchField) -> Option<&str> {
+        match field {
+            MatchField::Name => Some(&self.name),
+            MatchField::Class => self.class.as_deref(),
+            MatchField::Instance => self.instance.as_deref(),
+        }
+    }
+}
+
+/// How many of an entry's include patterns must match
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Any single include pattern is enough
+    #[default]
+    Any,
+    /// Every include pattern has to match
+    All,
+}
+
+/// Kind of focus change an entry reacts to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// Match against the focused window name
+    #[default]
+    Window,
+    /// Match against the focused workspace name
+    Workspace,
 }
 
 pub fn deserialize_string_or_seq_string<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
@@ -67,17 +319,57 @@ where
     deserializer.deserialize_any(Visitor(::std::marker::PhantomData))
 }
 
-#[derive(Debug)]
+/// Format of a config read from stdin, where there is no file extension to infer it from
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl From<ConfigFormat> for FileFormat {
+    fn from(format: ConfigFormat) -> Self {
+        match format {
+            ConfigFormat::Toml => FileFormat::Toml,
+            ConfigFormat::Yaml => FileFormat::Yaml,
+            ConfigFormat::Json => FileFormat::Json,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct I3WatcherConfig {
     pub entries: Vec<I3WatcherEntry>,
+    /// Delay before restoring the base layer after leaving a matched window
+    pub exit_grace_ms: Option<u64>,
+    /// Layer for focused windows no entry matches
+    pub fallback_layer: Option<u8>,
+    /// Minimum time between two layer changes, to stop overlapping rules from flapping
+    pub min_layer_interval_ms: Option<u64>,
+    /// Operation bytes to use instead of the built-in ones
+    pub protocol: Option<Opcodes>,
+    /// Keep the jiggler on exactly while a window of a `media` entry is focused, instead of
+    /// following the entries' own jiggler settings
+    pub jiggler_on_match: bool,
+    /// Substring patterns of names no entry ever matches, checked before any entry
+    always_exclude: Vec<Pattern>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 struct I3WatcherGlobalConfig {
     exclude: Option<Vec<String>>,
     include: Option<Vec<String>>,
     base_layer: Option<u8>,
     to_layer: Option<u8>,
+    exit_grace_ms: Option<u64>,
+    fallback_layer: Option<u8>,
+    min_layer_interval_ms: Option<u64>,
+    #[serde(default)]
+    jiggler_on_match: bool,
+    /// Names no entry ever matches, unlike `exclude` which only fills in entries without one
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_string_or_seq_string")]
+    always_exclude: Vec<String>,
 }
 
 impl I3WatcherGlobalConfig {
@@ -103,38 +395,295 @@ impl I3WatcherGlobalConfig {
 struct I3WatcherConfigFileStructure {
     global: I3WatcherGlobalConfig,
     entries: HashMap<String, I3WatcherEntry>,
+    protocol: Option<Opcodes>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProtocolFileStructure {
+    #[serde(default)]
+    protocol: Opcodes,
+}
+
+/// Reads only the `[protocol]` section of `config_file`, for commands that don't watch windows
+pub fn load_protocol(config_file: &str) -> Result<Opcodes, anyhow::Error> {
+    let config = Config::builder()
+        .add_source(config::File::with_name(config_file))
+        .build()?;
+
+    let ProtocolFileStructure { protocol } = config.try_deserialize()?;
+
+    Ok(protocol)
+}
+
+/// Keyboard settings applied together by `apply-profile`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub layer: Option<u8>,
+    /// RGB light color
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<RgbColor>,
+    /// Whether the mouse jiggler is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jiggler: Option<bool>,
+}
+
+/// A color written as `#rrggbb` in configs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RgbColor(pub u8, pub u8, pub u8);
+
+impl TryFrom<String> for RgbColor {
+    type Error = anyhow::Error;
+
+    fn try_from(color: String) -> Result<Self, Self::Error> {
+        let invalid = || anyhow::anyhow!("{:?} is not a #rrggbb color", color);
+        let hex = color
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6)
+            .ok_or_else(invalid)?;
+        let component = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|component| u8::from_str_radix(component, 16).ok())
+                .ok_or_else(invalid)
+        };
+
+        Ok(Self(component(0)?, component(2)?, component(4)?))
+    }
+}
+
+impl From<RgbColor> for String {
+    fn from(RgbColor(red, green, blue): RgbColor) -> Self {
+        format!("#{:02x}{:02x}{:02x}", red, green, blue)
+    }
+}
+
+impl Profile {
+    /// The profile as a `[profiles.<name>]` TOML block, ready to paste into a config
+    pub fn into_toml(self, name: &str) -> Result<String, anyhow::Error> {
+        let file = ProfilesFileStructure {
+            profiles: HashMap::from([(name.to_string(), self)]),
+        };
+
+        Ok(toml::to_string(&file)?)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ProfilesFileStructure {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// Reads the profile called `name` from the `[profiles]` section of `config_file`
+pub fn load_profile(config_file: &str, name: &str) -> Result<Profile, anyhow::Error> {
+    let config = Config::builder()
+        .add_source(config::File::with_name(config_file))
+        .build()?;
+
+    let ProfilesFileStructure { mut profiles } = config.try_deserialize()?;
+
+    profiles.remove(name).ok_or_else(|| {
+        let mut names = profiles.into_keys().collect::<Vec<_>>();
+        names.sort();
+        anyhow::anyhow!(
+            "No profile named {:?}, the profiles are: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+/// Patterns of a pattern file, one per line. Blank lines and `#` comments are skipped
+fn parse_pattern_lines(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Appends the patterns of `file`, relative to `base_dir`, to `patterns`
+fn extend_from_pattern_file(
+    patterns: &mut Vec<String>,
+    file: &Option<PathBuf>,
+    base_dir: &Path,
+) -> Result<(), anyhow::Error> {
+    if let Some(file) = file {
+        let path = base_dir.join(file);
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Could not read pattern file {:?}", path))?;
+        patterns.extend(parse_pattern_lines(&contents));
+    }
+
+    Ok(())
+}
+
+/// Include patterns that several entries with the same trigger list themselves, with the
+/// names of those entries. Patterns inherited from the global defaults don't count
+fn duplicate_includes(entries: &[(String, I3WatcherEntry)]) -> Vec<(String, Vec<String>)> {
+    let mut by_pattern: HashMap<(Trigger, &str), Vec<String>> = HashMap::new();
+    for (name, entry) in entries {
+        for pattern in &entry.include {
+            let names = by_pattern.entry((entry.trigger, pattern)).or_default();
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+
+    let mut duplicates = by_pattern
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .map(|((_, pattern), mut names)| {
+            names.sort();
+            (pattern.to_string(), names)
+        })
+        .collect::<Vec<_>>();
+    duplicates.sort();
+
+    duplicates
 }
 
 impl I3WatcherConfig {
-    pub fn load_config(config_file: &str) -> Result<Self, anyhow::Error> {
-        let config = Config::builder()
-            .add_source(config::File::with_name(config_file))
+    /// Loads the config from `config_file`, or from stdin in `stdin_format` when it is `-`.
+    /// Entries inherit unset fields from `[global]` only when `global_defaults` is set
+    pub fn load_config(
+        config_file: &str,
+        stdin_format: ConfigFormat,
+        global_defaults: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let builder = if config_file == "-" {
+            let mut contents = String::new();
+            std::io::stdin().read_to_string(&mut contents)?;
+            Config::builder().add_source(config::File::from_str(
+                &contents,
+                FileFormat::from(stdin_format),
+            ))
+        } else {
+            Config::builder().add_source(config::File::with_name(config_file))
+        };
+
+        let config = builder
             .add_source(config::Environment::with_prefix("DACTYL"))
             .build()?;
 
+        // pattern files are relative to the config file, or to the working directory for stdin
+        let base_dir = Path::new(config_file)
+            .parent()
+            .filter(|_| config_file != "-")
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+
         let I3WatcherConfigFileStructure {
             global: defaults,
             mut entries,
+            protocol,
         } = config.try_deserialize()?;
 
         let entries = entries
             .drain()
-            .map(|(_, v)| defaults.apply_defaults(v))
-            .collect::<Vec<_>>();
+            .map(|(name, mut v)| {
+                v.name = name.clone();
+                extend_from_pattern_file(&mut v.include, &v.include_file, &base_dir)
+                    .and_then(|_| {
+                        extend_from_pattern_file(&mut v.exclude, &v.exclude_file, &base_dir)
+                    })
+                    .with_context(|| format!("Invalid pattern file in entry {:?}", name))?;
+                Ok((name, v))
+            })
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+        for (pattern, names) in duplicate_includes(&entries) {
+            warn!(
+                "Include pattern {:?} is shared by entries {}, only one of them will match",
+                pattern,
+                names.join(", ")
+            );
+        }
+
+        let entries = entries
+            .into_iter()
+            .map(|(name, v)| {
+                let entry = if global_defaults {
+                    defaults.apply_defaults(v)
+                } else {
+                    v
+                };
+                entry
+                    .compile_patterns()
+                    .with_context(|| format!("Invalid pattern in entry {:?}", name))?;
+                Ok(entry)
+            })
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+        let always_exclude = defaults
+            .always_exclude
+            .iter()
+            .map(|pattern| MatchType::Substring.compile(pattern))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        Ok(Self { entries })
+        Ok(Self {
+            entries,
+            exit_grace_ms: defaults.exit_grace_ms,
+            fallback_layer: defaults.fallback_layer,
+            min_layer_interval_ms: defaults.min_layer_interval_ms,
+            protocol,
+            jiggler_on_match: defaults.jiggler_on_match,
+            always_exclude,
+        })
+    }
+
+    /// Whether `target` matches the global `always_exclude` patterns, so that focusing it
+    /// leaves the keyboard alone entirely, fallback layer and restores included
+    pub fn is_always_excluded(&self, target: &FocusTarget) -> bool {
+        let lowercase_name = target.name.to_lowercase();
+        self.always_exclude
+            .iter()
+            .any(|pattern| pattern.is_match(&target.name, &lowercase_name))
     }
 
-    pub fn matches_window(&self, window_name: &str) -> Option<&I3WatcherEntry> {
+    /// Finds the first entry for `trigger` that matches `target`
+    pub fn matches(&self, trigger: Trigger, target: &FocusTarget) -> Option<&I3WatcherEntry> {
+        if self.is_always_excluded(target) {
+            return None;
+        }
+
         self.entries.iter().find(|entry| {
-            let matches_include = entry
-                .include
-                .iter()
-                .any(|include| window_name.to_lowercase().contains(&include.to_lowercase()));
-            let matches_exclude = entry
-                .exclude
-                .iter()
-                .any(|exclude| window_name.to_lowercase().contains(&exclude.to_lowercase()));
+            if entry.trigger != trigger {
+                return false;
+            }
+            if entry.fullscreen.is_some() && target.fullscreen != entry.fullscreen {
+                return false;
+            }
+            if entry.workspace.is_some() && target.workspace != entry.workspace {
+                return false;
+            }
+            let Some(window_name) = target.field(entry.match_field) else {
+                return false;
+            };
+            if let Some(window_mode) = entry.window_mode {
+                let floating = window_mode == WindowMode::Floating;
+                if target.floating != Some(floating) {
+                    return false;
+                }
+            }
+            let lowercase_name = window_name.to_lowercase();
+            let matches_pattern = |pattern: &Option<Pattern>| {
+                pattern
+                    .as_ref()
+                    .is_some_and(|pattern| pattern.is_match(window_name, &lowercase_name))
+            };
+            let patterns = entry.patterns();
+            let matches_include = match entry.match_mode {
+                MatchMode::Any => patterns.include.iter().any(matches_pattern),
+                MatchMode::All => {
+                    !patterns.include.is_empty() && patterns.include.iter().all(matches_pattern)
+                }
+            };
+            let matches_exclude = patterns.exclude.iter().any(matches_pattern);
             matches_include && !matches_exclude
         })
     }
@@ -142,6 +691,275 @@ impl I3WatcherConfig {
 
 #[cfg(test)]
 mod test {
+    fn target(name: &str) -> super::FocusTarget {
+        super::FocusTarget {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_notify_command() {
+        let entry = super::I3WatcherEntry {
+            to_layer: Some(2),
+            on_new_layer: Some(4),
+            notify_template: Some("notify-send layer:{layer} {window}".to_string()),
+            ..Default::default()
+        };
+        let new_window = super::FocusTarget {
+            new_window: true,
+            ..target("mpv")
+        };
+
+        assert_eq!(
+            entry.notify_command(&target("a; rm -rf ~")),
+            Some(vec![
+                "notify-send".to_string(),
+                "layer:2".to_string(),
+                "a; rm -rf ~".to_string()
+            ])
+        );
+        assert_eq!(
+            entry.notify_command(&new_window),
+            Some(vec![
+                "notify-send".to_string(),
+                "layer:4".to_string(),
+                "mpv".to_string()
+            ])
+        );
+        assert_eq!(
+            super::I3WatcherEntry::default().notify_command(&target("a")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_patterns_compile_once() {
+        let entry = super::I3WatcherEntry {
+            include: vec!["FireFox".to_string()],
+            ..Default::default()
+        };
+        entry.compile_patterns().unwrap();
+
+        match &entry.patterns().include[..] {
+            [Some(super::Pattern::Substring(pattern))] => assert_eq!(pattern, "firefox"),
+            other => panic!("unexpected patterns: {:?}", other),
+        }
+
+        let regex = super::I3WatcherEntry {
+            include: vec!["(".to_string()],
+            match_type: super::MatchType::Regex,
+            ..Default::default()
+        };
+        assert!(regex.compile_patterns().is_err());
+        // compiled lazily, an invalid pattern never matches instead of failing
+        assert!(regex.patterns().include[0].is_none());
+    }
+
+    #[test]
+    fn test_profile_into_toml() {
+        let profile = super::Profile {
+            layer: Some(2),
+            color: Some(super::RgbColor(0x00, 0xff, 0x00)),
+            jiggler: Some(true),
+        };
+
+        assert_eq!(
+            profile.into_toml("gaming").unwrap(),
+            "[profiles.gaming]\nlayer = 2\ncolor = \"#00ff00\"\njiggler = true\n"
+        );
+    }
+
+    #[test]
+    fn test_profile_from_toml() {
+        let file: super::ProfilesFileStructure =
+            toml::from_str("[profiles.gaming]\nlayer = 2\ncolor = \"#00ff00\"\njiggler = true\n")
+                .unwrap();
+        let profile = &file.profiles["gaming"];
+
+        assert_eq!(profile.layer, Some(2));
+        assert_eq!(profile.color, Some(super::RgbColor(0x00, 0xff, 0x00)));
+        assert_eq!(profile.jiggler, Some(true));
+        assert!(toml::from_str::<super::ProfilesFileStructure>(
+            "[profiles.gaming]\ncolor = \"green\"\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_fullscreen() {
+        let config = super::I3WatcherConfig {
+            entries: vec![super::I3WatcherEntry {
+                include: vec!["mpv".to_string()],
+                fullscreen: Some(true),
+                to_layer: Some(3),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let fullscreen = |fullscreen| super::FocusTarget {
+            fullscreen,
+            ..target("mpv")
+        };
+
+        assert!(config
+            .matches(super::Trigger::Window, &fullscreen(Some(true)))
+            .is_some());
+        assert!(config
+            .matches(super::Trigger::Window, &fullscreen(Some(false)))
+            .is_none());
+        assert!(config
+            .matches(super::Trigger::Window, &fullscreen(None))
+            .is_none());
+    }
+
+    #[test]
+    fn test_always_exclude_short_circuits() {
+        let config = super::I3WatcherConfig {
+            entries: vec![super::I3WatcherEntry {
+                include: vec!["lock".to_string()],
+                to_layer: Some(1),
+                ..Default::default()
+            }],
+            always_exclude: vec![super::MatchType::Substring.compile("i3lock").unwrap()],
+            ..Default::default()
+        };
+
+        assert!(config.is_always_excluded(&target("i3LOCK")));
+        assert!(config
+            .matches(super::Trigger::Window, &target("i3LOCK"))
+            .is_none());
+        assert!(config
+            .matches(super::Trigger::Window, &target("xlock"))
+            .is_some());
+    }
+
+    #[test]
+    fn test_on_new_layer() {
+        let entry = super::I3WatcherEntry {
+            to_layer: Some(1),
+            on_new_layer: Some(3),
+            ..Default::default()
+        };
+        let new_window = super::FocusTarget {
+            new_window: true,
+            ..target("mpv")
+        };
+
+        assert_eq!(entry.layer_for(&new_window), Some(3));
+        assert_eq!(entry.layer_for(&target("mpv")), Some(1));
+        assert_eq!(
+            super::I3WatcherEntry {
+                to_layer: Some(1),
+                ..Default::default()
+            }
+            .layer_for(&new_window),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_workspace() {
+        let config = super::I3WatcherConfig {
+            entries: vec![super::I3WatcherEntry {
+                include: vec!["firefox".to_string()],
+                workspace: Some("2:web".to_string()),
+                to_layer: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let on = |workspace: Option<&str>| super::FocusTarget {
+            workspace: workspace.map(str::to_string),
+            ..target("firefox")
+        };
+
+        assert!(config
+            .matches(super::Trigger::Window, &on(Some("2:web")))
+            .is_some());
+        assert!(config
+            .matches(super::Trigger::Window, &on(Some("1:term")))
+            .is_none());
+        assert!(config.matches(super::Trigger::Window, &on(None)).is_none());
+    }
+
+    #[test]
+    fn test_match_field() {
+        let config = super::I3WatcherConfig {
+            entries: vec![super::I3WatcherEntry {
+                include: vec!["kitty".to_string()],
+                match_field: super::MatchField::Class,
+                to_layer: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let kitty = super::FocusTarget {
+            class: Some("kitty".to_string()),
+            ..target("vim")
+        };
+
+        assert!(config.matches(super::Trigger::Window, &kitty).is_some());
+        assert!(config
+            .matches(super::Trigger::Window, &target("kitty"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_ignores_title_changes() {
+        let entry = super::I3WatcherEntry {
+            ignore_title_changes: true,
+            ..Default::default()
+        };
+        let kitty = |title| super::FocusTarget {
+            class: Some("kitty".to_string()),
+            ..target(title)
+        };
+
+        assert!(entry.ignores_change(&kitty("make 10%"), &kitty("make 20%")));
+        assert!(!entry.ignores_change(
+            &kitty("make"),
+            &super::FocusTarget {
+                class: Some("firefox".to_string()),
+                ..target("make")
+            }
+        ));
+        assert!(!entry.ignores_change(&target("a"), &target("b")));
+        assert!(!super::I3WatcherEntry::default().ignores_change(&kitty("a"), &kitty("b")));
+    }
+
+    #[test]
+    fn test_duplicate_includes() {
+        let entry = |include: &[&str], trigger| super::I3WatcherEntry {
+            include: include.iter().map(|s| s.to_string()).collect(),
+            trigger,
+            ..Default::default()
+        };
+        let entries = vec![
+            (
+                "b".to_string(),
+                entry(&["firefox", "code"], super::Trigger::Window),
+            ),
+            ("a".to_string(), entry(&["firefox"], super::Trigger::Window)),
+            ("c".to_string(), entry(&["code"], super::Trigger::Workspace)),
+        ];
+
+        assert_eq!(
+            super::duplicate_includes(&entries),
+            vec![(
+                "firefox".to_string(),
+                vec!["a".to_string(), "b".to_string()]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_pattern_lines() {
+        let patterns = super::parse_pattern_lines("firefox\n\n# browsers\n  code  \n");
+
+        assert_eq!(patterns, vec!["firefox", "code"]);
+    }
+
     #[test]
     fn test_matches_window() {
         let config = super::I3WatcherConfig {
@@ -151,19 +969,207 @@ mod test {
                     exclude: vec![],
                     base_layer: None,
                     to_layer: None,
+                    ..Default::default()
                 },
                 super::I3WatcherEntry {
                     include: vec!["baz".to_string()],
                     exclude: vec!["bin".to_string()],
                     base_layer: None,
                     to_layer: None,
+                    ..Default::default()
                 },
             ],
+            ..Default::default()
         };
 
-        assert!(config.matches_window("foo").is_some());
-        assert!(config.matches_window("baz").is_some());
-        assert!(config.matches_window("baz bin").is_none());
-        assert!(config.matches_window("bin").is_none());
+        assert!(config
+            .matches(super::Trigger::Window, &target("foo"))
+            .is_some());
+        assert!(config
+            .matches(super::Trigger::Window, &target("baz"))
+            .is_some());
+        assert!(config
+            .matches(super::Trigger::Window, &target("baz bin"))
+            .is_none());
+        assert!(config
+            .matches(super::Trigger::Window, &target("bin"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_matches_by_trigger() {
+        let config = super::I3WatcherConfig {
+            entries: vec![super::I3WatcherEntry {
+                include: vec!["code".to_string()],
+                exclude: vec![],
+                base_layer: None,
+                to_layer: None,
+                trigger: super::Trigger::Workspace,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(config
+            .matches(super::Trigger::Window, &target("code"))
+            .is_none());
+        assert!(config
+            .matches(super::Trigger::Workspace, &target("2: code"))
+            .is_some());
+    }
+
+    #[test]
+    fn test_match_mode() {
+        let entry = |match_mode| super::I3WatcherEntry {
+            include: vec!["vim".to_string(), "project-x".to_string()],
+            match_mode,
+            ..Default::default()
+        };
+        let any = super::I3WatcherConfig {
+            entries: vec![entry(super::MatchMode::Any)],
+            ..Default::default()
+        };
+        let all = super::I3WatcherConfig {
+            entries: vec![entry(super::MatchMode::All)],
+            ..Default::default()
+        };
+
+        assert!(any
+            .matches(super::Trigger::Window, &target("vim"))
+            .is_some());
+        assert!(all
+            .matches(super::Trigger::Window, &target("vim"))
+            .is_none());
+        assert!(all
+            .matches(super::Trigger::Window, &target("vim ~/project-x"))
+            .is_some());
+    }
+
+    #[test]
+    fn test_window_mode() {
+        let config = super::I3WatcherConfig {
+            entries: vec![super::I3WatcherEntry {
+                include: vec!["mpv".to_string()],
+                window_mode: Some(super::WindowMode::Floating),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let floating = |floating| super::FocusTarget {
+            floating,
+            ..target("mpv")
+        };
+
+        assert!(config
+            .matches(super::Trigger::Window, &floating(Some(true)))
+            .is_some());
+        assert!(config
+            .matches(super::Trigger::Window, &floating(Some(false)))
+            .is_none());
+        assert!(config
+            .matches(super::Trigger::Window, &floating(None))
+            .is_none());
+    }
+
+    #[test]
+    fn test_glob_match_type() {
+        let config = super::I3WatcherConfig {
+            entries: vec![super::I3WatcherEntry {
+                include: vec!["firefox*".to_string(), "*vim*".to_string()],
+                exclude: vec!["*private browsing*".to_string()],
+                match_type: super::MatchType::Glob,
+                to_layer: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let matches = |name| {
+            config
+                .matches(super::Trigger::Window, &target(name))
+                .is_some()
+        };
+
+        assert!(matches("Firefox - GitHub"));
+        assert!(matches("kitty - NVIM"));
+        assert!(!matches("about firefox"));
+        assert!(!matches("Firefox - Private Browsing"));
+        assert!(super::MatchType::Glob.compile("[unclosed").is_err());
+    }
+
+    #[test]
+    fn test_regex_exclude_takes_precedence() {
+        let config = super::I3WatcherConfig {
+            entries: vec![super::I3WatcherEntry {
+                include: vec!["^firefox".to_string()],
+                exclude: vec![r"private browsing$".to_string()],
+                match_type: super::MatchType::Regex,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(config
+            .matches(super::Trigger::Window, &target("Firefox - docs"))
+            .is_some());
+        assert!(config
+            .matches(
+                super::Trigger::Window,
+                &target("Firefox - Private Browsing")
+            )
+            .is_none());
+        assert!(config
+            .matches(super::Trigger::Window, &target("docs - firefox"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_apply_defaults_keeps_own_include() {
+        let global = super::I3WatcherGlobalConfig {
+            include: Some(vec!["global".to_string()]),
+            exclude: Some(vec!["global-exclude".to_string()]),
+            ..Default::default()
+        };
+        let entry = global.apply_defaults(super::I3WatcherEntry {
+            include: vec!["own".to_string()],
+            ..Default::default()
+        });
+
+        assert_eq!(entry.include, vec!["own".to_string()]);
+        assert_eq!(entry.exclude, vec!["global-exclude".to_string()]);
+    }
+
+    #[test]
+    fn test_apply_defaults_fills_empty_include() {
+        let global = super::I3WatcherGlobalConfig {
+            include: Some(vec!["global".to_string()]),
+            ..Default::default()
+        };
+        let entry = global.apply_defaults(super::I3WatcherEntry::default());
+
+        assert_eq!(entry.include, vec!["global".to_string()]);
+        assert!(entry.exclude.is_empty());
+    }
+
+    #[test]
+    fn test_apply_defaults_layers_fall_back_only_when_unset() {
+        let global = super::I3WatcherGlobalConfig {
+            base_layer: Some(0),
+            to_layer: Some(1),
+            ..Default::default()
+        };
+
+        let entry = global.apply_defaults(super::I3WatcherEntry {
+            to_layer: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(entry.base_layer, Some(0));
+        assert_eq!(entry.to_layer, Some(3));
+
+        let entry = global.apply_defaults(super::I3WatcherEntry {
+            base_layer: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(entry.base_layer, Some(2));
+        assert_eq!(entry.to_layer, Some(1));
     }
 }
@@ -0,0 +1,141 @@
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use log::{debug, error};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+
+use crate::watcher::FocusTracker;
+
+/// Commands accepted on the control socket, one per line
+#[derive(Debug)]
+enum Command {
+    /// `layer <n>`: change to layer `n`
+    Change(u8),
+    /// `get-layer`: reply with the current layer number and name
+    Get,
+    /// `push-layer <n>`: change to layer `n`, remembering the current one for `pop-layer`
+    Push(u8),
+    /// `pop-layer`: change back to the layer the last `push-layer` left
+    Pop,
+    /// `jiggler on|off|toggle`: turn the mouse jiggler on or off, or toggle it with `None`
+    Jiggler(Option<bool>),
+}
+
+/// Layers left by `push-layer`, most recent last. Shared by all clients so scripted momentary
+/// activations nest, and locked across the whole read and change so they can't interleave
+type LayerStack = Arc<Mutex<Vec<u8>>>;
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, anyhow::Error> {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("layer"), Some(layer), None) => Ok(Self::Change(layer.parse()?)),
+            (Some("get-layer"), None, None) => Ok(Self::Get),
+            (Some("push-layer"), Some(layer), None) => Ok(Self::Push(layer.parse()?)),
+            (Some("pop-layer"), None, None) => Ok(Self::Pop),
+            (Some("jiggler"), Some("on"), None) => Ok(Self::Jiggler(Some(true))),
+            (Some("jiggler"), Some("off"), None) => Ok(Self::Jiggler(Some(false))),
+            (Some("jiggler"), Some("toggle"), None) => Ok(Self::Jiggler(None)),
+            _ => Err(anyhow!("unknown command {:?}", line)),
+        }
+    }
+
+    async fn execute(
+        self,
+        tracker: &FocusTracker,
+        stack: &LayerStack,
+    ) -> Result<String, anyhow::Error> {
+        match self {
+            Self::Change(layer) => {
+                tracker.change_layer(layer).await?;
+                Ok("ok".to_string())
+            }
+            Self::Get => {
+                let (layer, name) = tracker.current_layer().await?;
+                Ok(format!("layer {} {}", layer, name))
+            }
+            Self::Push(layer) => {
+                let mut stack = stack.lock().await;
+                let (current, _) = tracker.current_layer().await?;
+                // only remembered once the change went through, so a failed push doesn't leave
+                // an entry behind for a pop that has nothing to undo
+                tracker.change_layer(layer).await?;
+                stack.push(current);
+                Ok(format!("ok {}", stack.len()))
+            }
+            Self::Pop => {
+                let mut stack = stack.lock().await;
+                let layer = stack.pop().ok_or_else(|| anyhow!("layer stack is empty"))?;
+                if let Err(e) = tracker.change_layer(layer).await {
+                    // kept for a retry, as the keyboard is still on the pushed layer
+                    stack.push(layer);
+                    return Err(e);
+                }
+                Ok(format!("layer {}", layer))
+            }
+            Self::Jiggler(enabled) => {
+                let enabled = tracker.change_jiggler(enabled).await?;
+                Ok(format!("jiggler {}", if enabled { "on" } else { "off" }))
+            }
+        }
+    }
+}
+
+/// Serves the control line protocol on a unix socket at `path`, changing layers through
+/// `tracker` so they never race the watcher's own and keep to its minimum interval
+pub async fn serve(path: &Path, tracker: Arc<FocusTracker>) -> Result<(), anyhow::Error> {
+    // a previous run that was killed leaves its socket file behind, but never remove anything
+    // else that happens to be at that path
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(anyhow!(
+                "{:?} already exists and is not a socket, refusing to replace it",
+                path
+            ))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::bind(path)?;
+    debug!("control: listening on {:?}", path);
+    let stack = LayerStack::default();
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let tracker = tracker.clone();
+        let stack = stack.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, tracker, stack).await {
+                error!("Error: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_client(
+    stream: UnixStream,
+    tracker: Arc<FocusTracker>,
+    stack: LayerStack,
+) -> Result<(), anyhow::Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        debug!("control: received {:?}", line);
+
+        let reply = match Command::parse(&line) {
+            Ok(command) => command.execute(&tracker, &stack).await,
+            Err(e) => Err(e),
+        }
+        .unwrap_or_else(|e| format!("error: {}", e));
+
+        writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+    }
+
+    Ok(())
+}
@@ -1,13 +1,54 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
 use futures::StreamExt;
-use tokio_i3ipc::event::WindowData;
-use tokio_i3ipc::reply::Node;
+use tokio_i3ipc::event::{ShutdownChange, WindowData, WorkspaceData};
+use tokio_i3ipc::reply::{Floating, FullscreenMode, Node};
+
+use crate::config::FocusTarget;
+
+/// Describes `node` for config matching, if it has a name to match on
+pub fn focus_target(node: &Node) -> Option<FocusTarget> {
+    let properties = node.window_properties.as_ref();
+    Some(FocusTarget {
+        name: node.name.clone()?,
+        floating: node
+            .floating
+            .map(|floating| matches!(floating, Floating::AutoOn | Floating::UserOn)),
+        fullscreen: Some(!matches!(node.fullscreen_mode, FullscreenMode::None)),
+        class: properties.and_then(|properties| properties.class.clone()),
+        instance: properties.and_then(|properties| properties.instance.clone()),
+        new_window: false,
+        workspace: None,
+    })
+}
+
+/// Why a focus event subscription stopped
+pub enum SubscriptionEnd {
+    /// i3 is restarting or exiting
+    Shutdown(ShutdownChange),
+    /// No event arrived within the idle timeout
+    Idle,
+}
 
 #[async_trait::async_trait]
 pub trait I3Ext {
     async fn find_focused_node(&mut self) -> Result<Node, anyhow::Error>;
-    async fn subscribe_to_window_focus_events<F>(mut self, f: F) -> Result<(), anyhow::Error>
+    /// Name of the focused workspace, if any
+    async fn focused_workspace(&mut self) -> Result<Option<String>, anyhow::Error>;
+    /// Calls `on_window` on every window focus or focused window title change and `on_workspace`
+    /// on every workspace focus change, each with the previously and currently focused
+    /// window/workspace. The first focus of a newly created window arrives as a `New` change.
+    /// Returns once i3 shuts down, or once neither callback was called for `idle_timeout`
+    async fn subscribe_to_focus_events<F, G>(
+        mut self,
+        on_window: F,
+        on_workspace: G,
+        idle_timeout: Option<Duration>,
+    ) -> Result<SubscriptionEnd, anyhow::Error>
     where
-        F: Fn(Option<WindowData>, WindowData) -> Result<(), anyhow::Error> + Send;
+        F: Fn(Option<WindowData>, WindowData) -> Result<(), anyhow::Error> + Send,
+        G: Fn(Option<Node>, Node) -> Result<(), anyhow::Error> + Send;
 }
 
 pub trait I3NodeWalker {
@@ -22,13 +63,29 @@ impl I3Ext for tokio_i3ipc::I3 {
         tree.find_focused_node()
     }
 
-    async fn subscribe_to_window_focus_events<F>(mut self, f: F) -> Result<(), anyhow::Error>
+    async fn focused_workspace(&mut self) -> Result<Option<String>, anyhow::Error> {
+        Ok(self
+            .get_workspaces()
+            .await?
+            .into_iter()
+            .find(|workspace| workspace.focused)
+            .map(|workspace| workspace.name))
+    }
+
+    async fn subscribe_to_focus_events<F, G>(
+        mut self,
+        on_window: F,
+        on_workspace: G,
+        idle_timeout: Option<Duration>,
+    ) -> Result<SubscriptionEnd, anyhow::Error>
     where
         F: Fn(Option<WindowData>, WindowData) -> Result<(), anyhow::Error> + Send,
+        G: Fn(Option<Node>, Node) -> Result<(), anyhow::Error> + Send,
     {
         let subscription = self
             .subscribe([
                 tokio_i3ipc::event::Subscribe::Window,
+                tokio_i3ipc::event::Subscribe::Workspace,
                 tokio_i3ipc::event::Subscribe::Shutdown,
             ])
             .await?;
@@ -38,22 +95,79 @@ impl I3Ext for tokio_i3ipc::I3 {
         }
 
         let mut previous_ev: Option<WindowData> = None;
+        // windows that were created but not focused yet
+        let mut new_windows = HashSet::new();
+
+        // only events handed to `on_window` or `on_workspace` push the deadline back, so e.g.
+        // title changes of unfocused windows don't keep an idle watcher alive
+        let idle_deadline = || idle_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        let mut deadline = idle_deadline();
 
         let mut listener = self.listen();
-        while let Some(e) = listener.next().await {
+        loop {
+            let next = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, listener.next()).await {
+                    Ok(next) => next,
+                    Err(_) => return Ok(SubscriptionEnd::Idle),
+                },
+                None => listener.next().await,
+            };
+            let Some(e) = next else {
+                break;
+            };
+
             match e? {
-                tokio_i3ipc::event::Event::Window(ev) => {
-                    if let tokio_i3ipc::event::WindowChange::Focus = ev.change {
+                tokio_i3ipc::event::Event::Window(mut ev) => {
+                    // a title or fullscreen change of the focused window (e.g. switching
+                    // browser tabs) is handled like focusing it again in its new state
+                    let focus_changed = match ev.change {
+                        // i3 follows up with a focus event when the new window gets focus
+                        tokio_i3ipc::event::WindowChange::New => {
+                            new_windows.insert(ev.container.id);
+                            false
+                        }
+                        tokio_i3ipc::event::WindowChange::Focus => {
+                            // the first focus of a new window is delivered as its `New` event
+                            if new_windows.remove(&ev.container.id) {
+                                ev.change = tokio_i3ipc::event::WindowChange::New;
+                            }
+                            true
+                        }
+                        tokio_i3ipc::event::WindowChange::Title
+                        | tokio_i3ipc::event::WindowChange::FullscreenMode => ev.container.focused,
+                        tokio_i3ipc::event::WindowChange::Close => {
+                            new_windows.remove(&ev.container.id);
+                            false
+                        }
+                        _ => false,
+                    };
+                    if focus_changed {
                         let p_ev = Some(*ev.clone());
-                        f(previous_ev, *ev)?;
+                        on_window(previous_ev, *ev)?;
                         previous_ev = p_ev;
+                        deadline = idle_deadline();
+                    }
+                }
+                tokio_i3ipc::event::Event::Workspace(ev) => {
+                    if let WorkspaceData {
+                        change: tokio_i3ipc::event::WorkspaceChange::Focus,
+                        current: Some(current),
+                        old,
+                    } = *ev
+                    {
+                        on_workspace(old, current)?;
+                        deadline = idle_deadline();
                     }
                 }
-                tokio_i3ipc::event::Event::Shutdown(ev) => println!("shutdown: {:?}", ev),
+                tokio_i3ipc::event::Event::Shutdown(ev) => {
+                    return Ok(SubscriptionEnd::Shutdown(ev.change))
+                }
                 _ => unreachable!("unexpected not subscribed event"),
             }
         }
-        Ok(())
+        Err(anyhow::anyhow!(
+            "i3 closed the connection without shutting down"
+        ))
     }
 }
 
@@ -67,9 +181,11 @@ impl I3NodeWalker for tokio_i3ipc::reply::Node {
                 .first()
                 .ok_or_else(|| anyhow::anyhow!("no focused node"))?;
 
+            // floating windows live in their own list next to the tiled children
             node = node
                 .nodes
                 .into_iter()
+                .chain(node.floating_nodes)
                 .find(|n| n.id == *focused_node_id)
                 .ok_or_else(|| anyhow::anyhow!("focused node id"))?;
         }
@@ -77,3 +193,102 @@ impl I3NodeWalker for tokio_i3ipc::reply::Node {
         Ok(node)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::I3NodeWalker;
+
+    fn node(
+        id: usize,
+        focused: bool,
+        focus: &[usize],
+        nodes: Vec<serde_json::Value>,
+    ) -> serde_json::Value {
+        let rect = serde_json::json!({ "x": 0, "y": 0, "width": 0, "height": 0 });
+        serde_json::json!({
+            "id": id,
+            "name": format!("node {}", id),
+            "num": null,
+            "type": "con",
+            "layout": "splith",
+            "output": null,
+            "orientation": "none",
+            "border": "normal",
+            "scratchpad_state": "none",
+            "percent": null,
+            "rect": rect,
+            "window_rect": rect,
+            "deco_rect": rect,
+            "geometry": rect,
+            "window": null,
+            "window_properties": null,
+            "window_type": null,
+            "current_border_width": 0,
+            "urgent": false,
+            "marks": null,
+            "focused": focused,
+            "focus": focus,
+            "sticky": false,
+            "floating": null,
+            "floating_nodes": [],
+            "fullscreen_mode": 0,
+            "nodes": nodes,
+        })
+    }
+
+    fn tree(value: serde_json::Value) -> tokio_i3ipc::reply::Node {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_find_focused_node_returns_focused_root() {
+        let focused = tree(node(1, true, &[], vec![]))
+            .find_focused_node()
+            .unwrap();
+
+        assert_eq!(focused.id, 1);
+    }
+
+    #[test]
+    fn test_find_focused_node_follows_focus() {
+        let root = node(
+            1,
+            false,
+            &[3, 2],
+            vec![
+                node(2, false, &[], vec![]),
+                node(
+                    3,
+                    false,
+                    &[5],
+                    vec![node(4, false, &[], vec![]), node(5, true, &[], vec![])],
+                ),
+            ],
+        );
+
+        let focused = tree(root).find_focused_node().unwrap();
+
+        assert_eq!(focused.id, 5);
+        assert_eq!(focused.name.as_deref(), Some("node 5"));
+    }
+
+    #[test]
+    fn test_find_focused_node_follows_focus_into_floating() {
+        let mut workspace = node(2, false, &[4], vec![node(3, false, &[], vec![])]);
+        workspace["floating_nodes"] = serde_json::json!([node(4, true, &[], vec![])]);
+        let root = node(1, false, &[2], vec![workspace]);
+
+        let focused = tree(root).find_focused_node().unwrap();
+
+        assert_eq!(focused.id, 4);
+    }
+
+    #[test]
+    fn test_find_focused_node_inconsistent_tree() {
+        let missing_child = node(1, false, &[7], vec![node(2, true, &[], vec![])]);
+        let no_focus = node(1, false, &[], vec![node(2, true, &[], vec![])]);
+
+        assert!(tree(missing_child).find_focused_node().is_err());
+        assert!(tree(no_focus).find_focused_node().is_err());
+    }
+}
@@ -1,148 +1,1228 @@
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
 use anyhow::anyhow;
-use hidapi::HidApi;
-use log::trace;
+use hidapi::{DeviceInfo, HidApi};
+use log::{error, trace};
+use serde::{Deserialize, Serialize};
 
 const REPORT_LENGTH: usize = 32;
+/// How long to wait for the answer to an operation
+const READ_TIMEOUT_MS: i32 = 1000;
 
-#[derive(Debug)]
+/// Whether trace logs show reports as a hex dump instead of a flat array
+static HEX_DUMP: AtomicBool = AtomicBool::new(false);
+
+/// Makes trace logs show reports as a canonical hex dump with an ASCII gutter
+pub fn set_hex_dump(enabled: bool) {
+    HEX_DUMP.store(enabled, Ordering::Relaxed);
+}
+
+/// Formats `bytes` for the trace logs
+fn format_report(bytes: &[u8]) -> String {
+    if HEX_DUMP.load(Ordering::Relaxed) {
+        format!("\n{}", hex_dump(bytes))
+    } else {
+        format!("{:02x?}", bytes)
+    }
+}
+
+/// Formats `bytes` like `hexdump -C`: the offset, 16 bytes in hex and their printable ASCII
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex = chunk
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = chunk
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            format!("{:08x}  {:<47}  |{}|", line * 16, hex, ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct HidInfo {
     pub vendor_id: u16,
     pub product_id: u16,
     pub usage_page: u16,
     pub usage: u16,
+    /// Pick only this interface, for builds where several share the usage page and usage
+    pub interface_number: Option<i32>,
+    /// Case insensitive part of the product string to match instead of the vendor and product id
+    pub product: Option<String>,
+    /// Pick only the device with this serial number, e.g. one half of a split
+    pub serial_number: Option<String>,
+    /// Operation bytes the firmware on this device understands
+    pub opcodes: Opcodes,
+}
+
+impl HidInfo {
+    fn matches(&self, device: &DeviceInfo) -> bool {
+        let ids_match = match self.product {
+            Some(ref product) => device
+                .product_string()
+                .is_some_and(|name| name.to_lowercase().contains(&product.to_lowercase())),
+            None => device.vendor_id() == self.vendor_id && device.product_id() == self.product_id,
+        };
+
+        ids_match
+            && device.usage_page() == self.usage_page
+            && device.usage() == self.usage
+            && self
+                .interface_number
+                .is_none_or(|interface| device.interface_number() == interface)
+            && self
+                .serial_number
+                .as_ref()
+                .is_none_or(|serial| device.serial_number() == Some(serial.as_str()))
+    }
+
+    /// Whether anything beyond the ids and usage narrows down which device is meant
+    fn disambiguated(&self) -> bool {
+        self.interface_number.is_some() || self.product.is_some() || self.serial_number.is_some()
+    }
+}
+
+/// HID device as shown by `list-devices`
+#[derive(Debug, Serialize)]
+pub struct DeviceSummary {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub usage_page: u16,
+    pub usage: u16,
+    pub interface_number: i32,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    pub path: String,
+}
+
+impl From<&DeviceInfo> for DeviceSummary {
+    fn from(device: &DeviceInfo) -> Self {
+        Self {
+            vendor_id: device.vendor_id(),
+            product_id: device.product_id(),
+            usage_page: device.usage_page(),
+            usage: device.usage(),
+            interface_number: device.interface_number(),
+            manufacturer: device.manufacturer_string().map(str::to_string),
+            product: device.product_string().map(str::to_string),
+            serial_number: device.serial_number().map(str::to_string),
+            path: device.path().to_string_lossy().into_owned(),
+        }
+    }
+}
+
+pub fn list_devices() -> Result<Vec<DeviceSummary>> {
+    let api = HidApi::new()?;
+
+    Ok(api.device_list().map(DeviceSummary::from).collect())
 }
 
+/// Lists the devices exposing a QMK style raw HID interface: usage 0x61 on a vendor defined
+/// usage page
+pub fn scan_qmk_devices() -> Result<Vec<DeviceSummary>> {
+    let api = HidApi::new()?;
+
+    Ok(api
+        .device_list()
+        .filter(|device| (0xff00..=0xffff).contains(&device.usage_page()) && device.usage() == 0x61)
+        .map(DeviceSummary::from)
+        .collect())
+}
+
+/// Paths that answered a probe when several interfaces matched, so later connections skip it
+static PROBED_PATHS: Mutex<Vec<(HidInfo, CString)>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
 pub enum Operation {
     Bootloader,
     GetLayer,
     ChangeLayer(u8),
+    GetBattery,
+    Identify,
+    SetOledText(String),
+    GetRgbColor,
+    /// VIA `get_keyboard_value` for the given value id, e.g. 0x01 for uptime
+    ViaGetValue(u8),
+    GetLayerCount,
+    /// Sets the default layer, like QMK's `default_layer_set`, rather than moving to a layer
+    SetDefaultLayer(u8),
+    /// How much of the EEPROM/flash the firmware keeps its settings in is used
+    GetStorageInfo,
+    /// Holds a layer for the given milliseconds and releases it, timed by the firmware
+    TapLayer(u8, u16),
+    /// Sets how often the mouse jiggler moves the cursor, in milliseconds
+    SetJigglerInterval(u16),
+    GetJigglerInterval,
+    /// Whether the firmware's mouse jiggler is on
+    GetJiggler,
+    /// Turns the firmware's mouse jiggler on or off
+    SetJiggler(bool),
+    /// Sets the RGB light color, as red, green and blue
+    SetRgbColor(u8, u8, u8),
 }
 
 const OPERATION_BOOTLOADER: u8 = 0x42;
 const OPERATION_GET_LAYER: u8 = 0x43;
 const OPERATION_CHANGE_LAYER: u8 = 0x44;
+const OPERATION_GET_BATTERY: u8 = 0x45;
+const OPERATION_IDENTIFY: u8 = 0x46;
+const OPERATION_SET_OLED_TEXT: u8 = 0x47;
+const OPERATION_GET_RGB_COLOR: u8 = 0x48;
+const OPERATION_GET_LAYER_COUNT: u8 = 0x49;
+const OPERATION_SET_DEFAULT_LAYER: u8 = 0x4a;
+const OPERATION_GET_STORAGE_INFO: u8 = 0x4b;
+const OPERATION_TAP_LAYER: u8 = 0x4c;
+const OPERATION_SET_JIGGLER_INTERVAL: u8 = 0x4d;
+const OPERATION_GET_JIGGLER_INTERVAL: u8 = 0x4e;
+const OPERATION_GET_JIGGLER: u8 = 0x4f;
+const OPERATION_SET_JIGGLER: u8 = 0x50;
+const OPERATION_SET_RGB_COLOR: u8 = 0x51;
+
+/// Command id of VIA's `id_get_keyboard_value`. Part of the VIA protocol rather than of this
+/// firmware, so it is not overridable through `Opcodes`
+const VIA_GET_KEYBOARD_VALUE: u8 = 0x02;
+
+/// Operation bytes sent for each `Operation`. Firmware forks that renumbered their operations
+/// can override any of them from the `[protocol]` config section
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Opcodes {
+    pub bootloader: u8,
+    pub get_layer: u8,
+    pub change_layer: u8,
+    pub get_battery: u8,
+    pub identify: u8,
+    pub set_oled_text: u8,
+    pub get_rgb_color: u8,
+    pub get_layer_count: u8,
+    pub set_default_layer: u8,
+    pub get_storage_info: u8,
+    pub tap_layer: u8,
+    pub set_jiggler_interval: u8,
+    pub get_jiggler_interval: u8,
+    pub get_jiggler: u8,
+    pub set_jiggler: u8,
+    pub set_rgb_color: u8,
+}
+
+impl Default for Opcodes {
+    fn default() -> Self {
+        Self {
+            bootloader: OPERATION_BOOTLOADER,
+            get_layer: OPERATION_GET_LAYER,
+            change_layer: OPERATION_CHANGE_LAYER,
+            get_battery: OPERATION_GET_BATTERY,
+            identify: OPERATION_IDENTIFY,
+            set_oled_text: OPERATION_SET_OLED_TEXT,
+            get_rgb_color: OPERATION_GET_RGB_COLOR,
+            get_layer_count: OPERATION_GET_LAYER_COUNT,
+            set_default_layer: OPERATION_SET_DEFAULT_LAYER,
+            get_storage_info: OPERATION_GET_STORAGE_INFO,
+            tap_layer: OPERATION_TAP_LAYER,
+            set_jiggler_interval: OPERATION_SET_JIGGLER_INTERVAL,
+            get_jiggler_interval: OPERATION_GET_JIGGLER_INTERVAL,
+            get_jiggler: OPERATION_GET_JIGGLER,
+            set_jiggler: OPERATION_SET_JIGGLER,
+            set_rgb_color: OPERATION_SET_RGB_COLOR,
+        }
+    }
+}
+
+/// Set on the sequence byte of the last report of a multi-report operation
+const SEQUENCE_LAST: u8 = 0x80;
+
+/// Longest text `SetOledText` sends, as the 7 bits the sequence byte leaves number the reports
+/// 0 to 127, i.e. at most 128 reports of text
+const MAX_OLED_TEXT_LEN: usize = 128 * (REPORT_LENGTH - 2);
 
 impl Operation {
-    fn report(&self) -> [u8; REPORT_LENGTH] {
+    /// Whether the firmware answers this operation, as opposed to only acting on it
+    fn expects_answer(&self) -> bool {
+        !matches!(
+            self,
+            Self::Bootloader | Self::Identify | Self::SetOledText(_) | Self::TapLayer(..)
+        )
+    }
+
+    /// Reports written to the device for this operation, without the leading report id byte
+    pub fn reports(&self, opcodes: &Opcodes) -> Result<Vec<[u8; REPORT_LENGTH]>> {
         let mut ret = [0; REPORT_LENGTH];
         match self {
-            Self::Bootloader => ret[0] = OPERATION_BOOTLOADER,
+            Self::Bootloader => ret[0] = opcodes.bootloader,
             Self::GetLayer => {
-                ret[0] = OPERATION_GET_LAYER;
+                ret[0] = opcodes.get_layer;
             }
             Self::ChangeLayer(layer) => {
-                ret[0] = OPERATION_CHANGE_LAYER;
+                ret[0] = opcodes.change_layer;
+                ret[1] = *layer;
+            }
+            Self::GetBattery => ret[0] = opcodes.get_battery,
+            Self::Identify => ret[0] = opcodes.identify,
+            Self::SetOledText(text) => return Self::oled_text_reports(opcodes.set_oled_text, text),
+            Self::GetRgbColor => ret[0] = opcodes.get_rgb_color,
+            Self::ViaGetValue(id) => {
+                ret[0] = VIA_GET_KEYBOARD_VALUE;
+                ret[1] = *id;
+            }
+            Self::GetLayerCount => ret[0] = opcodes.get_layer_count,
+            Self::SetDefaultLayer(layer) => {
+                ret[0] = opcodes.set_default_layer;
                 ret[1] = *layer;
             }
+            Self::GetStorageInfo => ret[0] = opcodes.get_storage_info,
+            Self::TapLayer(layer, hold_ms) => {
+                ret[0] = opcodes.tap_layer;
+                ret[1] = *layer;
+                ret[2..4].copy_from_slice(&hold_ms.to_be_bytes());
+            }
+            Self::SetJigglerInterval(interval_ms) => {
+                ret[0] = opcodes.set_jiggler_interval;
+                ret[1..3].copy_from_slice(&interval_ms.to_be_bytes());
+            }
+            Self::GetJigglerInterval => ret[0] = opcodes.get_jiggler_interval,
+            Self::GetJiggler => ret[0] = opcodes.get_jiggler,
+            Self::SetJiggler(enabled) => {
+                ret[0] = opcodes.set_jiggler;
+                ret[1] = u8::from(*enabled);
+            }
+            Self::SetRgbColor(red, green, blue) => {
+                ret[0] = opcodes.set_rgb_color;
+                ret[1..4].copy_from_slice(&[*red, *green, *blue]);
+            }
         }
-        ret
+        Ok(vec![ret])
+    }
+
+    /// Splits `text` into `[opcode, sequence, chunk..]` reports. The sequence byte counts up
+    /// from 0 and has `SEQUENCE_LAST` set on the final report. Chunks are NUL padded
+    fn oled_text_reports(opcode: u8, text: &str) -> Result<Vec<[u8; REPORT_LENGTH]>> {
+        if text.len() > MAX_OLED_TEXT_LEN {
+            return Err(anyhow!(
+                "OLED text is {} bytes long, at most {} fit",
+                text.len(),
+                MAX_OLED_TEXT_LEN
+            ));
+        }
+        let chunks = text
+            .as_bytes()
+            .chunks(REPORT_LENGTH - 2)
+            .collect::<Vec<_>>();
+        let chunks = if chunks.is_empty() {
+            vec![&[][..]]
+        } else {
+            chunks
+        };
+        let last = chunks.len() - 1;
+
+        Ok(chunks
+            .into_iter()
+            .enumerate()
+            .map(|(sequence, chunk)| {
+                let mut ret = [0; REPORT_LENGTH];
+                ret[0] = opcode;
+                ret[1] = sequence as u8 | if sequence == last { SEQUENCE_LAST } else { 0 };
+                ret[2..2 + chunk.len()].copy_from_slice(chunk);
+                ret
+            })
+            .collect())
     }
 }
 
+#[derive(Debug)]
 pub enum KeyboardResponse {
     None,
     CurrentLayerNum(u8),
     CurrentLayer(u8, String),
+    Battery(u8),
+    RgbColor(u8, u8, u8),
+    /// VIA value id and the bytes following it
+    ViaValue(u8, Vec<u8>),
+    LayerCount(u8),
+    DefaultLayerNum(u8),
+    /// Bytes of storage used and available, both sent big endian after the operation byte
+    StorageInfo {
+        used: u16,
+        total: u16,
+    },
+    /// Jiggler interval in milliseconds, sent big endian. Setting it echoes the new interval
+    JigglerInterval(u16),
+    /// Whether the jiggler is on. Setting it echoes the new state
+    JigglerStatus(bool),
 }
 
-const KEYBOARD_RESPONSE_CURRENT_LAYER: u8 = 0x43;
-const KEYBOARD_RESPONSE_CURRENT_LAYER_NUM: u8 = 0x44;
-
 impl KeyboardResponse {
-    pub fn parse_response(buffer: [u8; REPORT_LENGTH]) -> Self {
+    /// Parses a report from the keyboard. Answers start with the byte of the operation they
+    /// answer, so they are recognized by the same `opcodes` the operations were sent with
+    pub fn parse_response(buffer: [u8; REPORT_LENGTH], opcodes: &Opcodes) -> Self {
         match buffer {
-            [KEYBOARD_RESPONSE_CURRENT_LAYER, layer, ..] => {
+            [op, layer, ..] if op == opcodes.get_layer => {
                 let name: String = buffer
                     .iter()
                     // first two bytes are the operation and layer number. Deconstructed above
                     .skip(2)
-                    .take_while(|c| c.is_ascii())
+                    // names are NUL padded, so stop at the first NUL or other control byte
+                    .take_while(|c| c.is_ascii() && !c.is_ascii_control())
                     .map(|c| *c as char)
                     .collect();
                 Self::CurrentLayer(layer, name)
             }
-            [KEYBOARD_RESPONSE_CURRENT_LAYER_NUM, layer, ..] => Self::CurrentLayerNum(layer),
+            [op, layer, ..] if op == opcodes.change_layer => Self::CurrentLayerNum(layer),
+            [op, percent, ..] if op == opcodes.get_battery => Self::Battery(percent),
+            [op, red, green, blue, ..]
+                if op == opcodes.get_rgb_color || op == opcodes.set_rgb_color =>
+            {
+                Self::RgbColor(red, green, blue)
+            }
+            [op, layers, ..] if op == opcodes.get_layer_count => Self::LayerCount(layers),
+            [op, layer, ..] if op == opcodes.set_default_layer => Self::DefaultLayerNum(layer),
+            [op, used_high, used_low, total_high, total_low, ..]
+                if op == opcodes.get_storage_info =>
+            {
+                Self::StorageInfo {
+                    used: u16::from_be_bytes([used_high, used_low]),
+                    total: u16::from_be_bytes([total_high, total_low]),
+                }
+            }
+            [op, high, low, ..]
+                if op == opcodes.set_jiggler_interval || op == opcodes.get_jiggler_interval =>
+            {
+                Self::JigglerInterval(u16::from_be_bytes([high, low]))
+            }
+            [op, enabled, ..] if op == opcodes.get_jiggler || op == opcodes.set_jiggler => {
+                Self::JigglerStatus(enabled != 0)
+            }
+            // VIA echoes the command and value id back, followed by the value
+            [VIA_GET_KEYBOARD_VALUE, id, ..] => Self::ViaValue(id, buffer[2..].to_vec()),
             _ => Self::None,
         }
     }
+
+    /// Whether this response is the one the firmware sends back for `operation`
+    fn answers(&self, operation: &Operation) -> bool {
+        !operation.expects_answer()
+            || matches!(
+                (operation, self),
+                (Operation::GetLayer, Self::CurrentLayer(..))
+                    | (Operation::ChangeLayer(_), Self::CurrentLayerNum(_))
+                    | (Operation::GetBattery, Self::Battery(_))
+                    | (Operation::GetRgbColor, Self::RgbColor(..))
+                    | (Operation::GetLayerCount, Self::LayerCount(_))
+                    | (Operation::SetDefaultLayer(_), Self::DefaultLayerNum(_))
+                    | (Operation::GetStorageInfo, Self::StorageInfo { .. })
+                    | (Operation::SetJigglerInterval(_), Self::JigglerInterval(_))
+                    | (Operation::GetJigglerInterval, Self::JigglerInterval(_))
+                    | (Operation::GetJiggler, Self::JigglerStatus(_))
+                    | (Operation::SetJiggler(_), Self::JigglerStatus(_))
+                    | (Operation::SetRgbColor(..), Self::RgbColor(..))
+            )
+            || matches!(
+                (operation, self),
+                (Operation::ViaGetValue(id), Self::ViaValue(answered, _)) if id == answered
+            )
+    }
 }
 
 pub struct Keyboard {
     device: hidapi::HidDevice,
+    opcodes: Opcodes,
+    /// Set once writing to or reading from the device failed, e.g. because it was unplugged
+    failed: Cell<bool>,
 }
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
-trait TransposableResult<T, U> {
-    fn transpose(self) -> std::result::Result<U, T>;
+#[derive(Debug)]
+pub enum KeyboardError {
+    DeviceNotFound {
+        hid_info: HidInfo,
+        /// Product ids of other devices from the same vendor
+        same_vendor_pids: Vec<u16>,
+        /// Vendor id the user likely meant, when `--vid` looks like hex digits read as decimal
+        hex_vid: Option<u16>,
+        /// Interface numbers of the devices that only differ from `hid_info` by interface
+        interface_numbers: Vec<i32>,
+    },
+    /// No report arrived in time for an operation that expects one
+    Timeout { operation: String },
+    /// The device went away before answering an operation that expects an answer
+    Disconnected { operation: String },
+    /// Several physical devices match, e.g. both halves of a split, and nothing picks one
+    MultipleDevices { devices: Vec<DeviceSummary> },
 }
 
-impl<T, U> TransposableResult<T, U> for std::result::Result<T, U> {
-    fn transpose(self) -> std::result::Result<U, T> {
+impl KeyboardError {
+    fn not_found(api: &HidApi, hid_info: &HidInfo) -> Self {
+        // the id hints are moot when matching on the product string
+        let by_ids = hid_info.product.is_none();
+        let mut same_vendor_pids = api
+            .device_list()
+            .filter(|device| by_ids && device.vendor_id() == hid_info.vendor_id)
+            .map(|device| device.product_id())
+            .filter(|pid| *pid != hid_info.product_id)
+            .collect::<Vec<_>>();
+        same_vendor_pids.sort_unstable();
+        same_vendor_pids.dedup();
+
+        let hex_vid = u16::from_str_radix(&hid_info.vendor_id.to_string(), 16)
+            .ok()
+            .filter(|_| by_ids)
+            .filter(|vid| api.device_list().any(|device| device.vendor_id() == *vid));
+
+        let any_interface = HidInfo {
+            interface_number: None,
+            ..hid_info.clone()
+        };
+        let mut interface_numbers = api
+            .device_list()
+            .filter(|device| hid_info.interface_number.is_some() && any_interface.matches(device))
+            .map(|device| device.interface_number())
+            .collect::<Vec<_>>();
+        interface_numbers.sort_unstable();
+        interface_numbers.dedup();
+
+        Self::DeviceNotFound {
+            hid_info: hid_info.clone(),
+            same_vendor_pids,
+            hex_vid,
+            interface_numbers,
+        }
+    }
+}
+
+impl std::fmt::Display for KeyboardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Ok(o) => Err(o),
-            Err(e) => Ok(e),
+            Self::DeviceNotFound {
+                hid_info,
+                same_vendor_pids,
+                hex_vid,
+                interface_numbers,
+            } => {
+                match hid_info.product {
+                    Some(ref product) => write!(f, "Unable to find device {:?}", product)?,
+                    None => write!(
+                        f,
+                        "Unable to find device {:04x}:{:04x}",
+                        hid_info.vendor_id, hid_info.product_id
+                    )?,
+                }
+                write!(
+                    f,
+                    " with usage page 0x{:04x} and usage 0x{:02x}",
+                    hid_info.usage_page, hid_info.usage
+                )?;
+                if let Some(interface) = hid_info.interface_number {
+                    write!(f, " on interface {}", interface)?;
+                }
+                if !interface_numbers.is_empty() {
+                    let interfaces = interface_numbers
+                        .iter()
+                        .map(|interface| interface.to_string())
+                        .collect::<Vec<_>>();
+                    write!(
+                        f,
+                        ". It is available on interfaces {}",
+                        interfaces.join(", ")
+                    )?;
+                }
+                if !same_vendor_pids.is_empty() {
+                    let pids = same_vendor_pids
+                        .iter()
+                        .map(|pid| format!("0x{:04x}", pid))
+                        .collect::<Vec<_>>();
+                    write!(
+                        f,
+                        ". Devices from the same vendor have product ids {}, is --pid wrong?",
+                        pids.join(", ")
+                    )?;
+                }
+                if let Some(vid) = hex_vid {
+                    write!(
+                        f,
+                        ". Ids are decimal unless prefixed with 0x, did you mean --vid 0x{:04x}?",
+                        vid
+                    )?;
+                }
+                write!(f, ". Run `list-devices` to see what is connected")
+            }
+            Self::Timeout { operation } => write!(
+                f,
+                "Keyboard did not answer {} within {}ms, does the firmware support it?",
+                operation, READ_TIMEOUT_MS
+            ),
+            Self::Disconnected { operation } => {
+                write!(f, "Keyboard disconnected before answering {}", operation)
+            }
+            Self::MultipleDevices { devices } => {
+                let devices = devices
+                    .iter()
+                    .map(|device| {
+                        format!(
+                            "{} (serial {}, interface {})",
+                            device.path,
+                            device.serial_number.as_deref().unwrap_or("none"),
+                            device.interface_number
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "Found {} matching devices: {}. Pick one with --serial or --interface, or \
+                     change all of them with `change-keyboard-layer --all-matching`",
+                    devices.len(),
+                    devices.join(", ")
+                )
+            }
         }
     }
 }
 
+impl std::error::Error for KeyboardError {}
+
 impl Keyboard {
     pub fn new(hid_info: &HidInfo) -> Result<Self> {
-        match HidApi::new() {
-            Ok(api) => {
-                let device = api
-                    .device_list()
-                    .find(|device| {
-                        device.vendor_id() == hid_info.vendor_id
-                            && device.product_id() == hid_info.product_id
-                            && device.usage_page() == hid_info.usage_page
-                            && device.usage() == hid_info.usage
-                    })
-                    .expect("Unable to find expected device");
+        Self::with_api(&HidApi::new()?, hid_info)
+    }
 
-                let macropad = api
-                    .open_path(device.path())
-                    .expect("Could not open HID device");
+    /// Connects through an existing `api`, skipping the cost of initializing a new one. The
+    /// device list is whatever `api` last enumerated, so refresh it first when reconnecting
+    pub fn with_api(api: &HidApi, hid_info: &HidInfo) -> Result<Self> {
+        let candidates = api
+            .device_list()
+            .filter(|device| hid_info.matches(device))
+            .collect::<Vec<_>>();
 
-                Ok(Keyboard { device: macropad })
+        match candidates.as_slice() {
+            [] => Err(KeyboardError::not_found(api, hid_info).into()),
+            [device] => Self::open(api, device.path(), &hid_info.opcodes),
+            _ if !hid_info.disambiguated() && Self::distinct_devices(&candidates) => {
+                Err(KeyboardError::MultipleDevices {
+                    devices: candidates
+                        .iter()
+                        .copied()
+                        .map(DeviceSummary::from)
+                        .collect(),
+                }
+                .into())
             }
-            Err(e) => Err(anyhow!(e)),
+            _ => Self::probe(api, hid_info, &candidates),
         }
     }
 
-    pub fn send_message(&self, operation: crate::Operation) -> Result<KeyboardResponse> {
-        let mut buffer = [0u8; REPORT_LENGTH + 1];
+    /// Whether `candidates` belong to more than one physical device rather than being several
+    /// nodes of the same one, which share a serial number. Halves flashed with the same serial
+    /// can't be told apart this way and are probed like a single device
+    fn distinct_devices(candidates: &[&DeviceInfo]) -> bool {
+        let mut serials = candidates
+            .iter()
+            .map(|device| device.serial_number())
+            .collect::<Vec<_>>();
+        serials.sort_unstable();
+        serials.dedup();
+
+        serials.len() > 1
+    }
+
+    /// Opens every device matching `hid_info` rather than picking one, e.g. both halves of a
+    /// split that each enumerate separately. Each result is paired with the device path
+    pub fn open_all(hid_info: &HidInfo) -> Result<Vec<(String, Result<Self>)>> {
+        let api = HidApi::new()?;
 
-        buffer[1..].copy_from_slice(&operation.report());
+        let keyboards = api
+            .device_list()
+            .filter(|device| hid_info.matches(device))
+            .map(|device| {
+                (
+                    device.path().to_string_lossy().into_owned(),
+                    Self::open(&api, device.path(), &hid_info.opcodes),
+                )
+            })
+            .collect::<Vec<_>>();
 
-        trace!("Writing: {:02x?}", buffer);
+        if keyboards.is_empty() {
+            return Err(KeyboardError::not_found(&api, hid_info).into());
+        }
 
-        let wrote = self
+        Ok(keyboards)
+    }
+
+    fn open(api: &HidApi, path: &CStr, opcodes: &Opcodes) -> Result<Self> {
+        trace!("Opening HID device at {:?}", path);
+
+        let device = api.open_path(path)?;
+
+        Ok(Keyboard {
+            device,
+            opcodes: opcodes.clone(),
+            failed: Cell::new(false),
+        })
+    }
+
+    /// Picks the first of several matching interfaces that answers a `GetLayer` correctly
+    fn probe(api: &HidApi, hid_info: &HidInfo, candidates: &[&DeviceInfo]) -> Result<Self> {
+        let cached = PROBED_PATHS
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(info, path)| {
+                info == hid_info
+                    && candidates
+                        .iter()
+                        .any(|device| device.path() == path.as_c_str())
+            })
+            .map(|(_, path)| path.clone());
+        if let Some(path) = cached {
+            return Self::open(api, &path, &hid_info.opcodes);
+        }
+
+        let mut failures = Vec::new();
+        for device in candidates {
+            trace!("Probing HID device at {:?}", device.path());
+
+            // a sibling node of the same device may still open when this one is not permitted
+            let keyboard = match Self::open(api, device.path(), &hid_info.opcodes) {
+                Ok(keyboard) => keyboard,
+                Err(e) => {
+                    trace!("Could not open {:?}: {:?}", device.path(), e);
+                    failures.push(format!("{:?}: could not open: {}", device.path(), e));
+                    continue;
+                }
+            };
+
+            match keyboard.send_message(Operation::GetLayer) {
+                Ok(_) => {
+                    trace!("Selected HID device at {:?}", device.path());
+                    PROBED_PATHS
+                        .lock()
+                        .unwrap()
+                        .push((hid_info.clone(), device.path().to_owned()));
+                    return Ok(keyboard);
+                }
+                Err(e) => {
+                    trace!("Probe of {:?} failed: {:?}", device.path(), e);
+                    failures.push(format!("{:?}: {}", device.path(), e));
+                }
+            }
+        }
+
+        Err(anyhow!(
+            "None of the {} matching devices answered a probe ({})",
+            candidates.len(),
+            failures.join("; ")
+        ))
+    }
+
+    /// Waits for the next report the keyboard sends on its own, without sending anything.
+    /// Returns `None` when nothing arrives within the read timeout
+    pub fn read_once(&self) -> Result<Option<[u8; REPORT_LENGTH]>> {
+        let mut buffer = [0u8; REPORT_LENGTH];
+
+        let read = self
             .device
-            .write(&buffer)
-            .expect("Could not write to HID device");
+            .read_timeout(&mut buffer, READ_TIMEOUT_MS)
+            .map_err(|e| self.fail(anyhow!("Could not read from HID device: {}", e)))?;
 
-        trace!("Wrote: {wrote:02x?} bytes");
+        Ok((read > 0).then_some(buffer))
+    }
 
-        let mut resp_buf = [0u8; REPORT_LENGTH];
+    /// Drops reports that arrived since the last read, e.g. ones the keyboard sent on its own,
+    /// so they aren't taken for the answer to the next operation
+    fn discard_pending(&self) -> Result<()> {
+        let mut buffer = [0u8; REPORT_LENGTH];
 
-        let response = self
+        while self
             .device
-            .read_timeout(&mut resp_buf, 1000)
-            .map(|_| ())
-            .transpose()
-            .and_then(|e| {
-                if e.to_string().contains("device disconnected") {
-                    Err(())
-                } else {
-                    Ok(e)
+            .read_timeout(&mut buffer, 0)
+            .map_err(|e| self.fail(anyhow!("Could not read from HID device: {}", e)))?
+            > 0
+        {
+            trace!("Discarding pending report: {}", format_report(&buffer));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the device failed a write or read, after which it should be reopened
+    fn failed(&self) -> bool {
+        self.failed.get()
+    }
+
+    fn fail(&self, error: anyhow::Error) -> anyhow::Error {
+        self.failed.set(true);
+        error
+    }
+
+    pub fn send_message(&self, operation: Operation) -> Result<KeyboardResponse> {
+        for report in operation.reports(&self.opcodes)? {
+            let mut buffer = [0u8; REPORT_LENGTH + 1];
+
+            buffer[1..].copy_from_slice(&report);
+
+            trace!("Writing: {}", format_report(&buffer));
+
+            let started = Instant::now();
+            let wrote = self
+                .device
+                .write(&buffer)
+                .map_err(|e| self.fail(anyhow!("Could not write to HID device: {}", e)))?;
+
+            trace!("Wrote: {} bytes in {:?}", wrote, started.elapsed());
+        }
+
+        let mut resp_buf = [0u8; REPORT_LENGTH];
+
+        let started = Instant::now();
+        let read = self.device.read_timeout(&mut resp_buf, READ_TIMEOUT_MS);
+        if read.is_err() {
+            self.failed.set(true);
+        }
+
+        trace!(
+            "Read: {:?} bytes in {:?}, response: {}",
+            read.as_ref().ok(),
+            started.elapsed(),
+            format_report(&resp_buf)
+        );
+
+        read_response(&operation, &self.opcodes, read, resp_buf)
+    }
+}
+
+/// Turns the outcome of reading the answer to `operation` into a response. For operations
+/// expecting an answer, reading nothing is a `KeyboardError::Timeout` and a disconnect is a
+/// `KeyboardError::Disconnected`. Other operations read a disconnect as an empty response, since
+/// `Bootloader` reboots the keyboard before it gets to answer
+fn read_response(
+    operation: &Operation,
+    opcodes: &Opcodes,
+    read: hidapi::HidResult<usize>,
+    buffer: [u8; REPORT_LENGTH],
+) -> Result<KeyboardResponse> {
+    let response = match read {
+        Ok(0) if operation.expects_answer() => {
+            return Err(KeyboardError::Timeout {
+                operation: format!("{:?}", operation),
+            }
+            .into())
+        }
+        Ok(_) => KeyboardResponse::parse_response(buffer, opcodes),
+        Err(e) if e.to_string().contains("device disconnected") => {
+            trace!("Device disconnected while reading: {}", e);
+            if operation.expects_answer() {
+                return Err(KeyboardError::Disconnected {
+                    operation: format!("{:?}", operation),
                 }
-            })
-            .transpose()
-            .map(|_| KeyboardResponse::parse_response(resp_buf))?;
+                .into());
+            }
+            KeyboardResponse::None
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if !response.answers(operation) {
+        return Err(anyhow!(
+            "Unexpected response {:?} to operation {:?}",
+            response,
+            operation
+        ));
+    }
+
+    Ok(response)
+}
+
+/// Gets the worker's keyboard, or the error connecting to it failed with
+type Job = Box<dyn FnOnce(Result<&Keyboard>) -> Result<()> + Send>;
+
+/// Runs keyboard jobs on a dedicated thread, so async callers never block on HID I/O
+#[derive(Clone)]
+pub struct KeyboardWorker {
+    jobs: std::sync::mpsc::Sender<Job>,
+}
+
+impl KeyboardWorker {
+    pub fn spawn(hid_info: HidInfo) -> Self {
+        let (jobs, queue) = std::sync::mpsc::channel::<Job>();
+
+        std::thread::spawn(move || {
+            let mut api = None;
+            let mut keyboard = None;
+            for job in queue {
+                let result = job(Self::connection(&mut api, &mut keyboard, &hid_info));
+                if let Err(e) = result {
+                    error!("Keyboard job failed: {:?}", e);
+                }
+            }
+        });
+
+        Self { jobs }
+    }
+
+    /// Keeps the keyboard in `slot` open across jobs, only reconnecting once it failed a write or
+    /// read, e.g. after being unplugged, or when the last connection attempt failed
+    fn connection<'a>(
+        api: &mut Option<HidApi>,
+        slot: &'a mut Option<Keyboard>,
+        hid_info: &HidInfo,
+    ) -> Result<&'a Keyboard> {
+        let reused = slot
+            .take()
+            .filter(|keyboard| !keyboard.failed() && keyboard.discard_pending().is_ok());
+        let keyboard = match reused {
+            Some(keyboard) => keyboard,
+            None => {
+                trace!("Connecting to the keyboard");
+                Keyboard::with_api(Self::shared_api(api)?, hid_info)?
+            }
+        };
+
+        Ok(slot.insert(keyboard))
+    }
+
+    /// Initializes `api` on first use and re-enumerates devices on every later one
+    fn shared_api(api: &mut Option<HidApi>) -> Result<&HidApi> {
+        match api {
+            Some(api) => {
+                api.refresh_devices()?;
+                Ok(api)
+            }
+            None => Ok(api.insert(HidApi::new()?)),
+        }
+    }
+
+    /// Sends `operation` through the worker and waits for its response
+    pub async fn request(&self, operation: Operation) -> Result<KeyboardResponse> {
+        let (sender, response) = tokio::sync::oneshot::channel();
+
+        // connection errors go back to the caller too, so it can tell e.g. a missing keyboard
+        // apart from other failures
+        self.queue(Box::new(move |keyboard| {
+            let _ = sender.send(keyboard.and_then(|keyboard| keyboard.send_message(operation)));
+            Ok(())
+        }))?;
+
+        response
+            .await
+            .map_err(|_| anyhow!("Keyboard worker has stopped"))?
+    }
+
+    /// Queues `job` to run against the worker's keyboard connection. Jobs run in submission order
+    pub fn submit<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce(&Keyboard) -> Result<()> + Send + 'static,
+    {
+        self.queue(Box::new(move |keyboard| job(keyboard?)))
+    }
+
+    fn queue(&self, job: Job) -> Result<()> {
+        self.jobs
+            .send(job)
+            .map_err(|_| anyhow!("Keyboard worker has stopped"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        trace!("Response: {:02x?}", resp_buf);
+    #[test]
+    fn test_oled_text_reports_are_sequenced() {
+        let text = "a".repeat(REPORT_LENGTH);
+        let reports = Operation::SetOledText(text)
+            .reports(&Opcodes::default())
+            .unwrap();
 
-        Ok(response)
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0][..2], [OPERATION_SET_OLED_TEXT, 0]);
+        assert_eq!(
+            reports[1][..2],
+            [OPERATION_SET_OLED_TEXT, 1 | SEQUENCE_LAST]
+        );
+        assert_eq!(&reports[1][2..4], b"aa");
+        assert_eq!(reports[1][4], 0);
+    }
+
+    #[test]
+    fn test_oled_text_too_long() {
+        let longest = "a".repeat(MAX_OLED_TEXT_LEN);
+        let reports = Operation::SetOledText(longest)
+            .reports(&Opcodes::default())
+            .unwrap();
+        assert_eq!(reports.len(), 128);
+        assert_eq!(reports[126][1], 126);
+        assert_eq!(reports[127][1], 127 | SEQUENCE_LAST);
+
+        let too_long = "a".repeat(MAX_OLED_TEXT_LEN + 1);
+        assert!(Operation::SetOledText(too_long)
+            .reports(&Opcodes::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_reports_use_overridden_opcodes() {
+        let opcodes = Opcodes {
+            change_layer: 0x50,
+            ..Default::default()
+        };
+        let reports = Operation::ChangeLayer(2).reports(&opcodes).unwrap();
+
+        assert_eq!(reports[0][..2], [0x50, 2]);
+        assert_eq!(
+            Operation::GetLayer.reports(&opcodes).unwrap()[0][0],
+            OPERATION_GET_LAYER
+        );
+    }
+
+    #[test]
+    fn test_tap_layer_report_packs_hold_big_endian() {
+        let reports = Operation::TapLayer(3, 500)
+            .reports(&Opcodes::default())
+            .unwrap();
+
+        assert_eq!(reports[0][..4], [OPERATION_TAP_LAYER, 3, 0x01, 0xf4]);
+    }
+
+    #[test]
+    fn test_jiggler_interval_round_trip() {
+        let reports = Operation::SetJigglerInterval(30000)
+            .reports(&Opcodes::default())
+            .unwrap();
+        assert_eq!(
+            reports[0][..3],
+            [OPERATION_SET_JIGGLER_INTERVAL, 0x75, 0x30]
+        );
+
+        let mut buffer = [0u8; REPORT_LENGTH];
+        buffer[..3].copy_from_slice(&[OPERATION_GET_JIGGLER_INTERVAL, 0x75, 0x30]);
+        let response = read_response(
+            &Operation::GetJigglerInterval,
+            &Opcodes::default(),
+            Ok(REPORT_LENGTH),
+            buffer,
+        );
+
+        assert!(matches!(
+            response,
+            Ok(KeyboardResponse::JigglerInterval(30000))
+        ));
+    }
+
+    #[test]
+    fn test_jiggler_round_trip() {
+        let reports = Operation::SetJiggler(true)
+            .reports(&Opcodes::default())
+            .unwrap();
+        assert_eq!(reports[0][..2], [OPERATION_SET_JIGGLER, 1]);
+
+        let mut buffer = [0u8; REPORT_LENGTH];
+        buffer[0] = OPERATION_GET_JIGGLER;
+        let response = read_response(
+            &Operation::GetJiggler,
+            &Opcodes::default(),
+            Ok(REPORT_LENGTH),
+            buffer,
+        );
+
+        assert!(matches!(
+            response,
+            Ok(KeyboardResponse::JigglerStatus(false))
+        ));
+    }
+
+    #[test]
+    fn test_set_rgb_color_round_trip() {
+        let reports = Operation::SetRgbColor(0x00, 0xff, 0x00)
+            .reports(&Opcodes::default())
+            .unwrap();
+        assert_eq!(reports[0][..4], [OPERATION_SET_RGB_COLOR, 0x00, 0xff, 0x00]);
+
+        let mut buffer = [0u8; REPORT_LENGTH];
+        buffer[..4].copy_from_slice(&[OPERATION_SET_RGB_COLOR, 0x00, 0xff, 0x00]);
+        let response = read_response(
+            &Operation::SetRgbColor(0x00, 0xff, 0x00),
+            &Opcodes::default(),
+            Ok(REPORT_LENGTH),
+            buffer,
+        );
+
+        assert!(matches!(
+            response,
+            Ok(KeyboardResponse::RgbColor(0x00, 0xff, 0x00))
+        ));
+    }
+
+    #[test]
+    fn test_overridden_get_layer_round_trip() {
+        let opcodes = Opcodes {
+            get_layer: 0x60,
+            ..Default::default()
+        };
+        assert_eq!(Operation::GetLayer.reports(&opcodes).unwrap()[0][0], 0x60);
+
+        // the fork answers with the renumbered byte too
+        let mut buffer = [0u8; REPORT_LENGTH];
+        buffer[..4].copy_from_slice(&[0x60, 1, b'F', b'n']);
+        let response = read_response(&Operation::GetLayer, &opcodes, Ok(REPORT_LENGTH), buffer);
+
+        match response {
+            Ok(KeyboardResponse::CurrentLayer(1, name)) => assert_eq!(name, "Fn"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        buffer[0] = OPERATION_GET_LAYER;
+        assert!(read_response(&Operation::GetLayer, &opcodes, Ok(REPORT_LENGTH), buffer).is_err());
+    }
+
+    fn disconnected() -> hidapi::HidError {
+        hidapi::HidError::HidApiError {
+            message: "unexpected poll error (device disconnected)".into(),
+        }
+    }
+
+    #[test]
+    fn test_read_response_parses_answer() {
+        let mut buffer = [0u8; REPORT_LENGTH];
+        buffer[..2].copy_from_slice(&[OPERATION_CHANGE_LAYER, 2]);
+
+        let response = read_response(
+            &Operation::ChangeLayer(2),
+            &Opcodes::default(),
+            Ok(REPORT_LENGTH),
+            buffer,
+        );
+
+        assert!(matches!(response, Ok(KeyboardResponse::CurrentLayerNum(2))));
+    }
+
+    #[test]
+    fn test_read_response_disconnect() {
+        let buffer = [0u8; REPORT_LENGTH];
+
+        assert!(matches!(
+            read_response(
+                &Operation::Bootloader,
+                &Opcodes::default(),
+                Err(disconnected()),
+                buffer
+            ),
+            Ok(KeyboardResponse::None)
+        ));
+        let disconnect = read_response(
+            &Operation::GetLayer,
+            &Opcodes::default(),
+            Err(disconnected()),
+            buffer,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            disconnect.downcast_ref(),
+            Some(KeyboardError::Disconnected { .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_response_errors() {
+        let buffer = [0u8; REPORT_LENGTH];
+
+        // a timeout reads no bytes, which no operation expecting an answer accepts
+        let timeout =
+            read_response(&Operation::GetLayer, &Opcodes::default(), Ok(0), buffer).unwrap_err();
+        assert!(matches!(
+            timeout.downcast_ref(),
+            Some(KeyboardError::Timeout { .. })
+        ));
+        assert!(read_response(&Operation::Identify, &Opcodes::default(), Ok(0), buffer).is_ok());
+
+        let error = hidapi::HidError::HidApiError {
+            message: "other".into(),
+        };
+        assert!(read_response(
+            &Operation::Bootloader,
+            &Opcodes::default(),
+            Err(error),
+            buffer
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_via_get_value_round_trip() {
+        let operation = Operation::ViaGetValue(0x01);
+        assert_eq!(
+            operation.reports(&Opcodes::default()).unwrap()[0][..2],
+            [VIA_GET_KEYBOARD_VALUE, 0x01]
+        );
+
+        let mut buffer = [0u8; REPORT_LENGTH];
+        buffer[..6].copy_from_slice(&[VIA_GET_KEYBOARD_VALUE, 0x01, 0, 0, 0x12, 0x34]);
+        let response = KeyboardResponse::parse_response(buffer, &Opcodes::default());
+
+        assert!(response.answers(&operation));
+        assert!(!response.answers(&Operation::ViaGetValue(0x04)));
+        match response {
+            KeyboardResponse::ViaValue(0x01, value) => assert_eq!(value[..4], [0, 0, 0x12, 0x34]),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        let mut bytes = [0u8; 18];
+        bytes[..4].copy_from_slice(b"\x43\x01ab");
+
+        assert_eq!(
+            hex_dump(&bytes),
+            "00000000  43 01 61 62 00 00 00 00 00 00 00 00 00 00 00 00  |C.ab............|\n\
+             00000010  00 00                                            |..|"
+        );
+    }
+
+    #[test]
+    fn test_parse_storage_info() {
+        let mut buffer = [0u8; REPORT_LENGTH];
+        buffer[..5].copy_from_slice(&[OPERATION_GET_STORAGE_INFO, 0x01, 0x2c, 0x04, 0x00]);
+
+        assert!(matches!(
+            KeyboardResponse::parse_response(buffer, &Opcodes::default()),
+            KeyboardResponse::StorageInfo {
+                used: 300,
+                total: 1024
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_layer_name_stops_at_nul() {
+        let mut buffer = [0u8; REPORT_LENGTH];
+        buffer[0] = OPERATION_GET_LAYER;
+        buffer[1] = 3;
+        buffer[2..6].copy_from_slice(b"Game");
+        // garbage after the NUL padding must not end up in the name
+        buffer[8..10].copy_from_slice(b"xy");
+
+        match KeyboardResponse::parse_response(buffer, &Opcodes::default()) {
+            KeyboardResponse::CurrentLayer(layer, name) => {
+                assert_eq!(layer, 3);
+                assert_eq!(name, "Game");
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
     }
 }
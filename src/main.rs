@@ -1,14 +1,27 @@
 mod config;
+mod control;
 mod i3;
 mod keyboard;
+mod watcher;
+mod x11;
 
-use clap::Parser;
+use std::io::IsTerminal;
+use std::sync::Arc;
+
+use anyhow::Context;
+use clap::{CommandFactory, Parser};
 use clap_num::maybe_hex;
-use log::{debug, error};
+use log::{debug, error, info, warn};
+use serde_json::json;
+use tokio_i3ipc::event::{ShutdownChange, WindowChange};
 
-use crate::i3::I3Ext;
+use crate::config::{FocusTarget, Trigger};
+use crate::i3::{I3Ext, SubscriptionEnd};
 
-use self::keyboard::{HidInfo, Keyboard, KeyboardResponse, Operation};
+use self::keyboard::{
+    HidInfo, Keyboard, KeyboardError, KeyboardResponse, KeyboardWorker, Opcodes, Operation,
+};
+use self::watcher::{FocusTracker, MatchReport};
 
 // const VENDOR_ID: u16 = 0x4b41; // Kasama
 // const PRODUCT_ID: u16 = 0x504d; // Macro pad
@@ -28,148 +41,1704 @@ struct App {
     /// Increases log verbosity on each appearance. -vvv will print out trace logs
     verbose: u8,
 
+    #[arg(long, value_enum)]
+    /// Log level to use instead of the one picked by the -v count
+    log_level: Option<LogLevel>,
+
+    #[arg(long)]
+    /// Show reports in trace logs as a hex dump with an ASCII gutter
+    hex_dump: bool,
+
     #[arg(short, long)]
     /// Suppress all output when set
     quiet: bool,
 
-    #[arg(long, default_value_t = VENDOR_ID, value_parser=maybe_hex::<u16>)]
+    #[arg(long)]
+    /// Print command output as JSON
+    json: bool,
+
+    #[arg(long, env = "DACTYL_VID", default_value_t = VENDOR_ID, value_parser=maybe_hex::<u16>)]
     /// HID Vendor ID
     vid: u16,
-    #[arg(long, default_value_t = PRODUCT_ID, value_parser=maybe_hex::<u16>)]
+    #[arg(long, env = "DACTYL_PID", default_value_t = PRODUCT_ID, value_parser=maybe_hex::<u16>)]
     /// HID Product ID
     pid: u16,
-    #[arg(short = 'p', long, default_value_t = USAGE_PAGE, value_parser=maybe_hex::<u16>)]
+    #[arg(short = 'p', long, env = "DACTYL_USAGE_PAGE", default_value_t = USAGE_PAGE, value_parser=maybe_hex::<u16>)]
     /// HID Usage Page
     usage_page: u16,
-    #[arg(short, long, default_value_t = USAGE, value_parser=maybe_hex::<u16>)]
+    #[arg(short, long, env = "DACTYL_USAGE", default_value_t = USAGE, value_parser=maybe_hex::<u16>)]
     /// HID Usage
     usage: u16,
+    #[arg(long)]
+    /// HID interface number, for keyboards exposing several interfaces with the same usage
+    interface: Option<i32>,
+    #[arg(long)]
+    /// Find the keyboard by part of its product string instead of --vid and --pid
+    product: Option<String>,
+    #[arg(long)]
+    /// Serial number of the device to use when several match, e.g. one half of a split
+    serial: Option<String>,
+
+    #[arg(long)]
+    /// Read operation byte overrides from the `[protocol]` section of this config file
+    protocol_config: Option<String>,
+    #[arg(skip)]
+    opcodes: Opcodes,
+
+    #[arg(long, value_enum, default_value_t = config::ConfigFormat::Toml)]
+    /// Format of the config when it is read from stdin with `--config -`
+    config_format: config::ConfigFormat,
+}
+
+/// Options shared by the window watching commands
+#[derive(clap::Args, Debug)]
+struct WatchOptions {
+    #[arg(long)]
+    /// Accept commands like `layer 2`, `get-layer`, `push-layer 3`/`pop-layer` or
+    /// `jiggler toggle` on a unix socket at this path
+    control_socket: Option<std::path::PathBuf>,
+    #[arg(long)]
+    /// Reload the config whenever the file changes. The minimum layer interval and protocol
+    /// still need a restart
+    watch_config: bool,
+    #[arg(long)]
+    /// Print the entry every focus change matches, without changing layers
+    print_matched: bool,
+    #[arg(long, conflicts_with = "print_matched")]
+    /// Print every focus change as a JSON object with the window, the entry it matched and the
+    /// layer it changes to, one per line
+    json_lines: bool,
+    #[arg(long)]
+    /// Only report matches, without changing layers
+    dry_run: bool,
+    #[arg(long)]
+    /// Don't fill in entries' unset fields from `[global]`, to test entries in isolation
+    no_global_defaults: bool,
+    #[arg(long)]
+    /// Only change layers while this file exists, to toggle switching by touching or removing it
+    enable_file: Option<std::path::PathBuf>,
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    /// Check the keyboard still answers every this many seconds, sending the current layer again
+    /// once it is back after a disconnect
+    heartbeat_secs: Option<u64>,
+    #[arg(long)]
+    /// Exit after this many seconds without focus changes, going back to the fallback layer
+    /// (or layer 0)
+    idle_exit_secs: Option<u64>,
+}
+
+impl WatchOptions {
+    fn idle_timeout(&self) -> Option<std::time::Duration> {
+        self.idle_exit_secs.map(std::time::Duration::from_secs)
+    }
+
+    /// Whether the watcher changes layers, rather than only reporting matches
+    fn changes_layers(&self) -> bool {
+        !(self.dry_run || self.print_matched)
+    }
+
+    fn match_report(&self) -> Option<MatchReport> {
+        if self.json_lines {
+            Some(MatchReport::JsonLines)
+        } else if self.print_matched {
+            Some(MatchReport::Text)
+        } else {
+            None
+        }
+    }
+
+    /// Whether focus changes should change layers right now, as toggled by `--enable-file`
+    fn switching_enabled(&self) -> bool {
+        let enabled = self.enable_file.as_ref().is_none_or(|path| path.exists());
+        if !enabled {
+            debug!("switching disabled, ignoring focus change");
+        }
+        enabled
+    }
 }
 
 #[derive(clap::Subcommand, Debug)]
 enum Commands {
-    PrintKeyboardLayer,
-    KeyboardBootloader,
+    PrintKeyboardLayer {
+        #[arg(long)]
+        /// Template for the output, with `{num}` and `{name}` replaced by the layer number and
+        /// name, and `{jiggler}` by `🖱 ` while the jiggler is on, e.g. `{jiggler}{name} ({num})`
+        format: Option<String>,
+    },
+    /// Reboot the keyboard into its bootloader to flash new firmware
+    KeyboardBootloader {
+        #[arg(short, long)]
+        /// Don't ask for confirmation. Required when stdin is not a terminal
+        yes: bool,
+    },
+    /// List all HID devices, to find the ids of a keyboard
+    ListDevices,
+    /// List only the devices that look like QMK/VIA keyboards
+    Scan,
     WatchI3Focus {
         #[arg(long, default_value = "false")]
         create_config: bool,
         #[arg(short, long)]
         config: Option<String>,
+        #[command(flatten)]
+        options: WatchOptions,
+    },
+    /// Show the focused window title on the keyboard's OLED display as focus changes
+    MirrorTitleToOled {
+        #[arg(long, default_value_t = 21)]
+        /// Characters of the title to show, one line of a 128 pixel wide display by default
+        max_chars: usize,
+    },
+    /// Periodically query the focused window instead of subscribing to focus events
+    PollWindowFocus {
+        #[arg(short, long)]
+        config: String,
+        #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..))]
+        /// Interval between focused window queries, in milliseconds
+        interval_ms: u64,
+        #[arg(long, value_enum, default_value_t = WindowSource::I3)]
+        /// Where to read the focused window from
+        source: WindowSource,
+        #[command(flatten)]
+        options: WatchOptions,
     },
     ChangeKeyboardLayer {
+        #[arg(allow_hyphen_values = true, value_parser = parse_layer_change)]
+        /// Layer number, or a change relative to the current layer like `+1` or `-1`
+        layer: LayerChange,
+        #[arg(long)]
+        /// Change the layer on every matching device, e.g. both halves of a split
+        all_matching: bool,
+        #[command(flatten)]
+        options: LayerChangeOptions,
+    },
+    /// Print the layer every time it changes, e.g. to feed a status bar
+    WatchLayer {
+        #[arg(long, default_value_t = 250, value_parser = clap::value_parser!(u64).range(1..))]
+        /// Interval between layer queries, in milliseconds
+        interval_ms: u64,
+    },
+    /// Wait until the keyboard is on a layer, exiting with an error if it times out
+    WaitForLayer {
         layer: u8,
+        #[arg(long, default_value_t = 10)]
+        /// Seconds to wait before giving up
+        timeout_secs: u64,
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+        /// Interval between layer queries, in milliseconds
+        interval_ms: u64,
     },
+    /// Force the keyboard back to a layer at a fixed interval, regardless of focus
+    PinLayer {
+        layer: u8,
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+        /// Seconds between re-applying the layer
+        interval_secs: u64,
+    },
+    /// Print the number of layers the firmware defines
+    LayerCount,
+    /// Print the battery level reported by wireless builds
+    Battery,
+    /// Print how much of the storage the firmware keeps its settings in is used
+    Storage,
+    /// Print layer, color, battery and jiggler at once, skipping whatever the firmware doesn't
+    /// support
+    Status,
+    /// Print the current RGB color as #rrggbb
+    GetColor,
+    /// Flash the keyboard LEDs for a few seconds to tell connected devices apart
+    Identify,
+    /// Switch to a layer for a while, then back to the layer that was active before
+    MomentaryLayer {
+        layer: u8,
+        #[arg(long, default_value_t = 1000)]
+        /// How long to stay on the layer, in milliseconds
+        duration_ms: u64,
+    },
+    /// Have the firmware hold a layer and release it on its own, like tapping a momentary layer
+    /// key. Needs firmware support, unlike `momentary-layer` which switches back from here
+    TapLayer {
+        layer: u8,
+        #[arg(long, default_value_t = 200)]
+        /// How long the firmware holds the layer, in milliseconds
+        hold_ms: u16,
+    },
+    /// Set how often the firmware's mouse jiggler moves the cursor
+    SetJigglerInterval {
+        /// Interval in milliseconds
+        ms: u16,
+    },
+    /// Print how often the firmware's mouse jiggler moves the cursor, in milliseconds
+    GetJigglerInterval,
+    /// Keep the mouse jiggler on, turning it back on whenever the firmware lost it, e.g. after a
+    /// USB suspend
+    KeepJiggle {
+        #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+        /// Seconds between checks of the jiggler
+        interval_secs: u64,
+    },
+    /// Apply the layers listed in a file one after another over a single connection, e.g. to
+    /// stress test the firmware
+    Replay {
+        /// File with one layer number per line. Blank lines and `#` comments are skipped
+        file: std::path::PathBuf,
+        #[arg(long, default_value_t = 0)]
+        /// Delay between two layer changes, in milliseconds
+        delay_ms: u64,
+    },
+    /// Print a shell completion script
+    Completions { shell: clap_complete::Shell },
+    /// Print a VIA keyboard value as hex bytes, for VIA enabled firmware
+    ViaValue {
+        #[arg(value_parser = maybe_hex::<u8>)]
+        /// VIA value id, like 0x01 for uptime or 0x04 for the firmware version
+        id: u8,
+    },
+    /// Print the reports an operation would send, without touching the keyboard
+    ShowReport {
+        #[arg(value_enum)]
+        operation: OperationName,
+        /// Operation arguments: the layer, the OLED text, the VIA value id, or the layer and
+        /// hold time for `tap-layer`
+        args: Vec<String>,
+    },
+    /// Apply the layer, RGB color and jiggler state of a profile from the `[profiles]` section
+    ApplyProfile {
+        name: String,
+        #[arg(short, long)]
+        config: String,
+    },
+    /// Print the current layer, color and jiggler state as a profile to paste into the config
+    Snapshot {
+        #[arg(long, default_value = "snapshot")]
+        /// Name of the printed profile
+        name: String,
+    },
+    /// Print which entry of a config matches a window name and the layer it would apply,
+    /// without a keyboard or window manager
+    TestMatch {
+        #[arg(short, long)]
+        config: String,
+        /// Window name, or workspace name with `--workspace`
+        name: String,
+        #[arg(long)]
+        /// Match against workspace entries instead of window ones
+        workspace: bool,
+        #[arg(long)]
+        /// Window class, for entries matching on `class`
+        class: Option<String>,
+        #[arg(long)]
+        /// Window instance, for entries matching on `instance`
+        instance: Option<String>,
+    },
+    /// Print every report the keyboard sends, without sending anything, to debug firmware
+    Monitor,
+    /// Show text on the keyboard's OLED display
+    SetOled { text: String },
+}
+
+// How `change-keyboard-layer` applies a change, flattened into its arguments
+#[derive(clap::Args, Debug, Clone, Copy)]
+struct LayerChangeOptions {
+    #[arg(long)]
+    /// Number of layers, so relative changes wrap around instead of stopping at the ends.
+    /// Asked from the keyboard when not given
+    layers: Option<u8>,
+    #[arg(long)]
+    /// Set the default layer instead of moving to the layer
+    default: bool,
+    #[arg(long, conflicts_with = "default")]
+    /// Read the layer back after changing it, and retry once if it didn't take
+    verify: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LayerChange {
+    Absolute(u8),
+    Relative(i16),
+}
+
+fn parse_layer_change(s: &str) -> Result<LayerChange, String> {
+    if s.starts_with(['+', '-']) {
+        s.parse()
+            .map(LayerChange::Relative)
+            .map_err(|e| e.to_string())
+    } else {
+        s.parse()
+            .map(LayerChange::Absolute)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl LayerChange {
+    /// Layer to change to from `current`, wrapping relative changes around `layers` when known
+    fn target(self, current: u8, layers: Option<u8>) -> u8 {
+        match self {
+            Self::Absolute(layer) => layer,
+            Self::Relative(offset) => {
+                // in i32, so offsets near `i16::MAX` can't overflow
+                let layer = i32::from(current) + i32::from(offset);
+                match layers {
+                    Some(layers) if layers > 0 => layer.rem_euclid(i32::from(layers)) as u8,
+                    _ => layer.clamp(0, i32::from(u8::MAX)) as u8,
+                }
+            }
+        }
+    }
+}
+
+/// Where `poll-window-focus` reads the focused window from
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum WindowSource {
+    /// i3's IPC socket
+    I3,
+    /// `_NET_ACTIVE_WINDOW` on the X11 root window, set by most other window managers
+    X11,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Trace => log::Level::Trace,
+        }
+    }
+}
+
+/// CLI names of the operations `show-report` knows
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum OperationName {
+    Bootloader,
+    GetLayer,
+    ChangeLayer,
+    GetBattery,
+    Identify,
+    SetOledText,
+    GetRgbColor,
+    ViaGetValue,
+    GetLayerCount,
+    SetDefaultLayer,
+    GetStorageInfo,
+    TapLayer,
+    SetJigglerInterval,
+    GetJigglerInterval,
+    GetJiggler,
+    SetJiggler,
+    SetRgbColor,
+}
+
+impl OperationName {
+    fn operation(self, args: &[String]) -> Result<Operation, anyhow::Error> {
+        let number = || -> Result<u8, anyhow::Error> {
+            match args {
+                [arg] => maybe_hex::<u8>(arg).map_err(anyhow::Error::msg),
+                _ => Err(anyhow::anyhow!("{:?} takes a single number", self)),
+            }
+        };
+
+        Ok(match self {
+            Self::Bootloader => Operation::Bootloader,
+            Self::GetLayer => Operation::GetLayer,
+            Self::ChangeLayer => Operation::ChangeLayer(number()?),
+            Self::GetBattery => Operation::GetBattery,
+            Self::Identify => Operation::Identify,
+            Self::SetOledText => Operation::SetOledText(args.join(" ")),
+            Self::GetRgbColor => Operation::GetRgbColor,
+            Self::ViaGetValue => Operation::ViaGetValue(number()?),
+            Self::GetLayerCount => Operation::GetLayerCount,
+            Self::SetDefaultLayer => Operation::SetDefaultLayer(number()?),
+            Self::GetStorageInfo => Operation::GetStorageInfo,
+            Self::TapLayer => match args {
+                [layer, hold_ms] => Operation::TapLayer(
+                    maybe_hex::<u8>(layer).map_err(anyhow::Error::msg)?,
+                    hold_ms.parse()?,
+                ),
+                _ => return Err(anyhow::anyhow!("{:?} takes a layer and a hold time", self)),
+            },
+            Self::SetJigglerInterval => match args {
+                [interval_ms] => Operation::SetJigglerInterval(interval_ms.parse()?),
+                _ => return Err(anyhow::anyhow!("{:?} takes an interval", self)),
+            },
+            Self::GetJigglerInterval => Operation::GetJigglerInterval,
+            Self::GetJiggler => Operation::GetJiggler,
+            Self::SetJiggler => Operation::SetJiggler(number()? != 0),
+            Self::SetRgbColor => match args {
+                [red, green, blue] => Operation::SetRgbColor(
+                    maybe_hex::<u8>(red).map_err(anyhow::Error::msg)?,
+                    maybe_hex::<u8>(green).map_err(anyhow::Error::msg)?,
+                    maybe_hex::<u8>(blue).map_err(anyhow::Error::msg)?,
+                ),
+                _ => return Err(anyhow::anyhow!("{:?} takes red, green and blue", self)),
+            },
+        })
+    }
+}
+
+/// Anything that can be asked for the currently focused window
+#[async_trait::async_trait]
+trait ActiveWindowGetter {
+    async fn active_window(&mut self) -> Result<Option<FocusTarget>, anyhow::Error>;
+}
+
+#[async_trait::async_trait]
+impl ActiveWindowGetter for tokio_i3ipc::I3 {
+    async fn active_window(&mut self) -> Result<Option<FocusTarget>, anyhow::Error> {
+        let Some(mut target) = i3::focus_target(&self.find_focused_node().await?) else {
+            return Ok(None);
+        };
+        target.workspace = self.focused_workspace().await?;
+
+        Ok(Some(target))
+    }
+}
+
+#[async_trait::async_trait]
+impl ActiveWindowGetter for x11::X11 {
+    async fn active_window(&mut self) -> Result<Option<FocusTarget>, anyhow::Error> {
+        self.focus_target()
+    }
+}
+
+/// Changes the keyboard back to `layer` when dropped, even if the command is interrupted
+struct RestoreLayer<'a> {
+    keyboard: &'a Keyboard,
+    layer: u8,
+}
+
+impl Drop for RestoreLayer<'_> {
+    fn drop(&mut self) {
+        debug!("Restoring layer {}", self.layer);
+        print_error(
+            self.keyboard
+                .send_message(Operation::ChangeLayer(self.layer)),
+        );
+    }
+}
+
+/// Exit status of a failed command, so scripts can tell a missing keyboard (2) and a keyboard
+/// that didn't answer (3) from other errors (1)
+fn exit_code(e: &anyhow::Error) -> i32 {
+    match e.downcast_ref::<KeyboardError>() {
+        Some(KeyboardError::DeviceNotFound { .. }) => 2,
+        Some(KeyboardError::Timeout { .. }) => 3,
+        Some(KeyboardError::Disconnected { .. }) => 1,
+        Some(KeyboardError::MultipleDevices { .. }) | None => 1,
+    }
+}
+
+/// Sends `operation`, or returns `None` when the firmware lacks it. Firmware that lacks an
+/// operation doesn't answer it, which surfaces as a `KeyboardError::Timeout`
+fn query_supported(
+    keyboard: &Keyboard,
+    operation: Operation,
+) -> Result<Option<KeyboardResponse>, anyhow::Error> {
+    let name = format!("{:?}", operation);
+    match keyboard.send_message(operation) {
+        Ok(response) => Ok(Some(response)),
+        Err(e) if matches!(e.downcast_ref(), Some(KeyboardError::Timeout { .. })) => {
+            debug!("Skipping unsupported operation {}", name);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Pings the keyboard through `tracker` every `interval`, and once it answers again after
+/// failing, sends the layer it lost while unplugged
+async fn heartbeat(tracker: Arc<FocusTracker>, interval: std::time::Duration) {
+    let mut disconnected = false;
+    loop {
+        tokio::time::sleep(interval).await;
+
+        match tracker.heartbeat().await {
+            Err(e) if !disconnected => {
+                warn!("Keyboard stopped answering: {}", e);
+                disconnected = true;
+            }
+            Err(_) => {}
+            Ok(()) if disconnected => {
+                info!("Keyboard is back, restoring its layer");
+                disconnected = false;
+                print_error(tracker.resend_layer());
+            }
+            Ok(()) => {}
+        }
+    }
 }
 
 fn print_error<T, E: std::fmt::Debug>(r: Result<T, E>) {
     r.map(|_| ()).unwrap_or_else(|e| error!("Error: {:?}", e));
 }
 
+/// Connects to i3 again after a restart, giving it a few seconds to bring its IPC socket back up
+async fn reconnect_to_i3() -> Result<tokio_i3ipc::I3, anyhow::Error> {
+    let mut attempts = 0;
+    loop {
+        match tokio_i3ipc::I3::connect().await {
+            Ok(i3) => return Ok(i3),
+            Err(e) if attempts < 20 => {
+                debug!("i3 not back yet: {}", e);
+                attempts += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// A single thread is enough to drive the window event streams, as long as nothing blocks it.
+// HID I/O is blocking, so the watchers hand it over to a `KeyboardWorker` thread.
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), anyhow::Error> {
-    let app = App::parse();
+    let mut app = App::parse();
 
     if !app.quiet {
-        simple_logger::init_with_level(match app.verbose {
-            0 => log::Level::Error,
-            1 => log::Level::Info,
-            2 => log::Level::Debug,
-            3.. => log::Level::Trace,
+        simple_logger::init_with_level(match (app.log_level, app.verbose) {
+            (Some(level), _) => level.into(),
+            (None, 0) => log::Level::Error,
+            (None, 1) => log::Level::Info,
+            (None, 2) => log::Level::Debug,
+            (None, 3..) => log::Level::Trace,
         })?;
     }
 
-    match app.command {
-        Commands::PrintKeyboardLayer => print_error(app.print_keyboard_layer()),
-        Commands::KeyboardBootloader => print_error(app.keyboard_bootloader()),
+    keyboard::set_hex_dump(app.hex_dump);
+
+    if let Some(ref protocol_config) = app.protocol_config {
+        app.opcodes = config::load_protocol(protocol_config)?;
+    }
+
+    let result = match app.command {
+        Commands::PrintKeyboardLayer { ref format } => app.print_keyboard_layer(format.as_deref()),
+        Commands::KeyboardBootloader { yes } => app.keyboard_bootloader(yes),
+        Commands::ListDevices => {
+            keyboard::list_devices().and_then(|devices| app.print_devices(devices))
+        }
+        Commands::Scan => {
+            keyboard::scan_qmk_devices().and_then(|devices| app.print_devices(devices))
+        }
         Commands::WatchI3Focus {
             create_config,
             ref config,
+            ref options,
         } => {
             if create_config {
                 return Ok(());
             }
             if let Some(config) = config {
-                let config = config::I3WatcherConfig::load_config(config)?;
-                print_error(app.watch_i3_focus(config).await)
+                app.watch_i3_focus(config, options).await
             } else {
-                error!("No window names provided")
+                Err(anyhow::anyhow!("No window names provided"))
             }
         }
-        Commands::ChangeKeyboardLayer { layer } => print_error(app.change_keyboard_layer(layer)),
+        Commands::MirrorTitleToOled { max_chars } => app.mirror_title_to_oled(max_chars).await,
+        Commands::PollWindowFocus {
+            ref config,
+            interval_ms,
+            source,
+            ref options,
+        } => match source {
+            WindowSource::I3 => {
+                let i3 = tokio_i3ipc::I3::connect().await?;
+                app.poll_window_focus(i3, config, interval_ms, options)
+                    .await
+            }
+            WindowSource::X11 => {
+                let x11 = x11::X11::connect()?;
+                app.poll_window_focus(x11, config, interval_ms, options)
+                    .await
+            }
+        },
+        Commands::ChangeKeyboardLayer {
+            layer,
+            all_matching,
+            options,
+        } => {
+            if all_matching {
+                app.change_all_keyboard_layers(layer, options)
+            } else {
+                app.change_keyboard_layer(layer, options)
+            }
+        }
+        Commands::TapLayer { layer, hold_ms } => app.tap_layer(layer, hold_ms),
+        Commands::SetJigglerInterval { ms } => {
+            app.jiggler_interval(Operation::SetJigglerInterval(ms))
+        }
+        Commands::GetJigglerInterval => app.jiggler_interval(Operation::GetJigglerInterval),
+        Commands::KeepJiggle { interval_secs } => app.keep_jiggle(interval_secs).await,
+        Commands::Replay { ref file, delay_ms } => app.replay(file, delay_ms).await,
+        Commands::MomentaryLayer { layer, duration_ms } => {
+            app.momentary_layer(layer, duration_ms).await
+        }
+        Commands::WatchLayer { interval_ms } => app.watch_layer(interval_ms).await,
+        Commands::WaitForLayer {
+            layer,
+            timeout_secs,
+            interval_ms,
+        } => app.wait_for_layer(layer, timeout_secs, interval_ms).await,
+        Commands::PinLayer {
+            layer,
+            interval_secs,
+        } => app.pin_layer(layer, interval_secs).await,
+        Commands::Battery => app.print_battery(),
+        Commands::Storage => app.print_storage(),
+        Commands::LayerCount => app.print_layer_count(),
+        Commands::Status => app.print_status(),
+        Commands::GetColor => app.print_color(),
+        Commands::Identify => app.identify(),
+        Commands::SetOled { ref text } => app.set_oled(text),
+        Commands::ViaValue { id } => app.print_via_value(id),
+        Commands::Monitor => app.monitor(),
+        Commands::TestMatch {
+            ref config,
+            ref name,
+            workspace,
+            ref class,
+            ref instance,
+        } => {
+            let trigger = if workspace {
+                config::Trigger::Workspace
+            } else {
+                config::Trigger::Window
+            };
+            let target = config::FocusTarget {
+                name: name.clone(),
+                class: class.clone(),
+                instance: instance.clone(),
+                ..Default::default()
+            };
+            app.test_match(config, trigger, &target)
+        }
+        Commands::Snapshot { ref name } => app.snapshot(name),
+        Commands::ApplyProfile {
+            ref name,
+            ref config,
+        } => app.apply_profile(config, name),
+        Commands::ShowReport {
+            operation,
+            ref args,
+        } => app.show_report(operation, args),
+        Commands::Completions { shell } => {
+            clap_complete::generate(
+                shell,
+                &mut App::command(),
+                env!("CARGO_PKG_NAME"),
+                &mut std::io::stdout(),
+            );
+            Ok(())
+        }
     };
 
+    if let Err(e) = result {
+        error!("Error: {:?}", e);
+        std::process::exit(exit_code(&e));
+    }
+
     Ok(())
 }
 
 impl App {
-    fn connect_to_keyboard(&self) -> Result<Keyboard, anyhow::Error> {
-        Keyboard::new(&HidInfo {
+    fn hid_info(&self) -> HidInfo {
+        HidInfo {
             vendor_id: self.vid,
             product_id: self.pid,
             usage_page: self.usage_page,
             usage: self.usage,
-        })
+            interface_number: self.interface,
+            product: self.product.clone(),
+            serial_number: self.serial.clone(),
+            opcodes: self.opcodes.clone(),
+        }
+    }
+
+    /// Opens a new connection on every call, so commands connect once and pass the keyboard
+    /// down to their helpers. Only the long running `pin-layer`, `watch-layer` and `keep-jiggle`
+    /// connect again, to pick the keyboard back up after a replug
+    fn connect_to_keyboard(&self) -> Result<Keyboard, anyhow::Error> {
+        Keyboard::new(&self.hid_info())
+    }
+
+    /// Prints `value` when `--json` is set, and `text` otherwise
+    fn output(&self, value: serde_json::Value, text: impl std::fmt::Display) {
+        if self.json {
+            println!("{}", value);
+        } else {
+            println!("{}", text);
+        }
+    }
+
+    /// Starts the keyboard worker for a watcher. `protocol` is the watcher config's `[protocol]`
+    /// section, which takes precedence over `--protocol-config`
+    fn start_watcher(&self, protocol: Option<&Opcodes>) -> KeyboardWorker {
+        let mut hid_info = self.hid_info();
+        if let Some(protocol) = protocol {
+            hid_info.opcodes = protocol.clone();
+        }
+        KeyboardWorker::spawn(hid_info)
+    }
+
+    /// Loads the watcher config and starts tracking focus with it. With `--watch-config`, the
+    /// returned file watcher reloads the config on change for as long as it is kept alive
+    fn start_tracker(
+        &self,
+        config_file: &str,
+        options: &WatchOptions,
+    ) -> Result<(Arc<FocusTracker>, Option<notify::RecommendedWatcher>), anyhow::Error> {
+        let global_defaults = !options.no_global_defaults;
+        let config =
+            config::I3WatcherConfig::load_config(config_file, self.config_format, global_defaults)?;
+        let tracker = Arc::new(FocusTracker::new(
+            self.start_watcher(config.protocol.as_ref()),
+            config,
+            options.match_report(),
+            !options.changes_layers(),
+        ));
+
+        if let Some(path) = options.control_socket.clone() {
+            let tracker = tracker.clone();
+            tokio::spawn(async move { print_error(control::serve(&path, tracker).await) });
+        }
+
+        if let (Some(secs), true) = (options.heartbeat_secs, options.changes_layers()) {
+            tokio::spawn(heartbeat(
+                tracker.clone(),
+                std::time::Duration::from_secs(secs),
+            ));
+        }
+
+        if !options.watch_config {
+            return Ok((tracker, None));
+        }
+        if config_file == "-" {
+            return Err(anyhow::anyhow!(
+                "--watch-config needs a config file, not stdin"
+            ));
+        }
+
+        let config_path = std::path::PathBuf::from(config_file);
+        let stem = config_path.file_stem().map(ToOwned::to_owned);
+        let (config_file, config_format) = (config_file.to_string(), self.config_format);
+        let reloaded = tracker.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) if event.kind.is_create() || event.kind.is_modify() => event,
+                    Ok(_) => return,
+                    Err(e) => return error!("Error watching the config: {:?}", e),
+                };
+                if !event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_stem() == stem.as_deref())
+                {
+                    return;
+                }
+                match config::I3WatcherConfig::load_config(
+                    &config_file,
+                    config_format,
+                    global_defaults,
+                ) {
+                    Ok(config) => {
+                        info!("Reloaded config from {}", config_file);
+                        reloaded.reload(config);
+                    }
+                    Err(e) => error!("Keeping the previous config, reload failed: {:?}", e),
+                }
+            })?;
+
+        // editors often save by replacing the file, so watch the directory rather than the file
+        let directory = config_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(std::path::Path::new("."));
+        notify::Watcher::watch(&mut watcher, directory, notify::RecursiveMode::NonRecursive)?;
+
+        Ok((tracker, Some(watcher)))
+    }
+
+    async fn watch_i3_focus(
+        &self,
+        config_file: &str,
+        options: &WatchOptions,
+    ) -> Result<(), anyhow::Error> {
+        let (tracker, _config_watcher) = self.start_tracker(config_file, options)?;
+        let mut i3 = tokio_i3ipc::I3::connect().await?;
+
+        loop {
+            // window events don't say which workspace the window is on, so follow workspace focus
+            let current_workspace = std::sync::Mutex::new(i3.focused_workspace().await?);
+            let end = i3
+                .subscribe_to_focus_events(
+                    |prev_ev, window_data| {
+                        let node = window_data.container;
+                        debug!("win: current focused node: {:?}", node);
+
+                        if !options.switching_enabled() {
+                            return Ok(());
+                        }
+                        if let Some(mut target) = i3::focus_target(&node) {
+                            target.new_window = window_data.change == WindowChange::New;
+                            target.workspace = current_workspace.lock().unwrap().clone();
+                            let prev_target =
+                                prev_ev.and_then(|ev| i3::focus_target(&ev.container));
+                            tracker.focus_changed(
+                                Trigger::Window,
+                                prev_target.as_ref(),
+                                &target,
+                            )?;
+                        }
+
+                        Ok(())
+                    },
+                    |old, workspace| {
+                        debug!("ws: current focused workspace: {:?}", workspace.name);
+                        current_workspace
+                            .lock()
+                            .unwrap()
+                            .clone_from(&workspace.name);
+
+                        if !options.switching_enabled() {
+                            return Ok(());
+                        }
+                        if let Some(target) = i3::focus_target(&workspace) {
+                            let old_target = old.as_ref().and_then(i3::focus_target);
+                            tracker.focus_changed(
+                                Trigger::Workspace,
+                                old_target.as_ref(),
+                                &target,
+                            )?;
+                        }
+
+                        Ok(())
+                    },
+                    options.idle_timeout(),
+                )
+                .await?;
+
+            match end {
+                SubscriptionEnd::Shutdown(ShutdownChange::Exit) => {
+                    info!("i3 exited, stopping watcher");
+                    return Ok(());
+                }
+                SubscriptionEnd::Shutdown(ShutdownChange::Restart) => {
+                    info!("i3 is restarting, reconnecting");
+                    i3 = reconnect_to_i3().await?;
+                }
+                SubscriptionEnd::Idle => {
+                    info!("No focus changes for a while, stopping watcher");
+                    return tracker.reset_layer().await;
+                }
+            }
+        }
     }
 
-    async fn watch_i3_focus(&self, config: config::I3WatcherConfig) -> Result<(), anyhow::Error> {
-        let i3 = tokio_i3ipc::I3::connect().await?;
-
-        i3.subscribe_to_window_focus_events(|prev_ev, window_data| {
-            let node = window_data.container;
-            debug!("win: current focused node: {:?}", node);
-
-            if let Some(name) = node.name {
-                if let Some(entry) = config.matches_window(&name) {
-                    debug!("win: matched window: {:?}", entry);
-                    let keyboard = self.connect_to_keyboard()?;
-                    entry
-                        .to_layer
-                        .map(|layer| keyboard.send_message(Operation::ChangeLayer(layer)));
-                } else if let Some(ev) = prev_ev {
-                    if let Some(name) = ev.container.name {
-                        if let Some(entry) = config.matches_window(&name) {
-                            debug!("win: exited matching window: {:?}", entry);
-                            let keyboard = self.connect_to_keyboard()?;
-                            entry
-                                .base_layer
-                                .map(|layer| keyboard.send_message(Operation::ChangeLayer(layer)));
+    async fn mirror_title_to_oled(&self, max_chars: usize) -> Result<(), anyhow::Error> {
+        let worker = KeyboardWorker::spawn(self.hid_info());
+        let shown = std::sync::Mutex::new(None::<String>);
+        let mut i3 = tokio_i3ipc::I3::connect().await?;
+
+        loop {
+            let end = i3
+                .subscribe_to_focus_events(
+                    |_, window_data| {
+                        let Some(title) = window_data.container.name else {
+                            return Ok(());
+                        };
+                        let title = title.chars().take(max_chars).collect::<String>();
+
+                        // title events repeat the same text, e.g. while a terminal redraws
+                        let mut shown = shown.lock().unwrap();
+                        if shown.as_ref() == Some(&title) {
+                            return Ok(());
                         }
+                        debug!("oled: showing {:?}", title);
+                        *shown = Some(title.clone());
+
+                        worker.submit(move |keyboard| {
+                            keyboard
+                                .send_message(Operation::SetOledText(title))
+                                .map(|_| ())
+                        })
+                    },
+                    |_, _| Ok(()),
+                    None,
+                )
+                .await?;
+
+            match end {
+                SubscriptionEnd::Shutdown(ShutdownChange::Restart) => {
+                    info!("i3 is restarting, reconnecting");
+                    i3 = reconnect_to_i3().await?;
+                }
+                _ => {
+                    info!("i3 exited, stopping");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn poll_window_focus<W: ActiveWindowGetter>(
+        &self,
+        mut getter: W,
+        config_file: &str,
+        interval_ms: u64,
+        options: &WatchOptions,
+    ) -> Result<(), anyhow::Error> {
+        let (tracker, _config_watcher) = self.start_tracker(config_file, options)?;
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(interval_ms));
+        let mut prev_target: Option<FocusTarget> = None;
+        let mut last_change = std::time::Instant::now();
+
+        loop {
+            interval.tick().await;
+
+            let target = match getter.active_window().await {
+                Ok(target) => target,
+                Err(error) => {
+                    warn!("Could not get the focused window, skipping: {:#}", error);
+                    continue;
+                }
+            };
+            if target == prev_target {
+                if let Some(idle_timeout) = options.idle_timeout() {
+                    if last_change.elapsed() >= idle_timeout {
+                        info!("No focus changes for a while, stopping watcher");
+                        return tracker.reset_layer().await;
                     }
                 }
+                continue;
             }
+            last_change = std::time::Instant::now();
+            debug!("poll: focused window changed to {:?}", target);
 
-            Ok(())
-        })
-        .await?;
+            if let Some(target) = target.as_ref().filter(|_| options.switching_enabled()) {
+                tracker.focus_changed(Trigger::Window, prev_target.as_ref(), target)?;
+            }
+            prev_target = target;
+        }
+    }
+
+    fn print_keyboard_layer(&self, format: Option<&str>) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        let response = keyboard.send_message(Operation::GetLayer)?;
+
+        if let KeyboardResponse::CurrentLayer(layer, name) = response {
+            debug!("Current layer: {} ({})", layer, name);
+            let format = format.unwrap_or("⌨: {name}");
+            // only asked for when shown, as firmware without a jiggler takes a timeout to answer
+            let jiggler = if format.contains("{jiggler}") {
+                match query_supported(&keyboard, Operation::GetJiggler)? {
+                    Some(KeyboardResponse::JigglerStatus(enabled)) => Some(enabled),
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            let text = format
+                .replace("{num}", &layer.to_string())
+                .replace("{name}", &name)
+                .replace("{jiggler}", if jiggler == Some(true) { "🖱 " } else { "" });
+            let mut value = json!({ "layer": layer, "name": name });
+            if let Some(enabled) = jiggler {
+                value["jiggler"] = json!(enabled);
+            }
+            self.output(value, text);
+        }
 
         Ok(())
     }
 
-    fn print_keyboard_layer(&self) -> Result<(), anyhow::Error> {
+    fn change_keyboard_layer(
+        &self,
+        change: LayerChange,
+        options: LayerChangeOptions,
+    ) -> Result<(), anyhow::Error> {
         let keyboard = self.connect_to_keyboard()?;
 
-        let response = keyboard.send_message(Operation::GetLayer)?;
+        let layer = Self::apply_layer_change(&keyboard, change, options)?;
 
-        if let KeyboardResponse::CurrentLayer(_, name) = response {
-            println!("⌨: {}", name);
+        if options.default {
+            self.output(
+                json!({ "default_layer": layer }),
+                format_args!("Default layer: {}", layer),
+            );
+        } else {
+            self.output(
+                json!({ "layer": layer }),
+                format_args!("Current layer: {}", layer),
+            );
         }
 
         Ok(())
     }
 
-    fn change_keyboard_layer(&self, layer: u8) -> Result<(), anyhow::Error> {
+    fn change_all_keyboard_layers(
+        &self,
+        change: LayerChange,
+        options: LayerChangeOptions,
+    ) -> Result<(), anyhow::Error> {
+        let results = Keyboard::open_all(&self.hid_info())?
+            .into_iter()
+            .map(|(path, keyboard)| {
+                let layer = keyboard
+                    .and_then(|keyboard| Self::apply_layer_change(&keyboard, change, options));
+                (path, layer)
+            })
+            .collect::<Vec<_>>();
+
+        let failed = results.iter().filter(|(_, layer)| layer.is_err()).count();
+
+        if self.json {
+            let results = results
+                .iter()
+                .map(|(path, layer)| match layer {
+                    Ok(layer) => json!({ "path": path, "layer": layer }),
+                    Err(e) => json!({ "path": path, "error": e.to_string() }),
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::Value::from(results));
+        } else {
+            for (path, layer) in &results {
+                match layer {
+                    Ok(layer) => println!("{}: Current layer: {}", path, layer),
+                    Err(e) => println!("{}: Error: {}", path, e),
+                }
+            }
+        }
+
+        if failed > 0 {
+            return Err(anyhow::anyhow!(
+                "{} of {} devices failed to change layer",
+                failed,
+                results.len()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Changes `keyboard` to the layer `change` points at, or makes it the default layer, and
+    /// returns the layer it reports back
+    fn apply_layer_change(
+        keyboard: &Keyboard,
+        change: LayerChange,
+        options: LayerChangeOptions,
+    ) -> Result<u8, anyhow::Error> {
+        let layer = match change {
+            LayerChange::Absolute(layer) => layer,
+            LayerChange::Relative(_) => {
+                // firmware without a layer count makes relative changes stop at the ends instead
+                let layers = options.layers.or_else(|| Self::layer_count(keyboard).ok());
+                match keyboard.send_message(Operation::GetLayer)? {
+                    KeyboardResponse::CurrentLayer(current, _) => change.target(current, layers),
+                    _ => return Err(anyhow::anyhow!("Keyboard did not report its current layer")),
+                }
+            }
+        };
+
+        let operation = || {
+            if options.default {
+                Operation::SetDefaultLayer(layer)
+            } else {
+                Operation::ChangeLayer(layer)
+            }
+        };
+        let changed = match keyboard.send_message(operation())? {
+            KeyboardResponse::CurrentLayerNum(layer) | KeyboardResponse::DefaultLayerNum(layer) => {
+                layer
+            }
+            _ => return Err(anyhow::anyhow!("Keyboard did not report the new layer")),
+        };
+
+        if options.verify {
+            Self::verify_layer(keyboard, layer)?;
+        }
+
+        Ok(changed)
+    }
+
+    /// Reads the layer back after a change, sending the change once more if it didn't take,
+    /// since USB can drop a report
+    fn verify_layer(keyboard: &Keyboard, layer: u8) -> Result<(), anyhow::Error> {
+        let current = || match keyboard.send_message(Operation::GetLayer)? {
+            KeyboardResponse::CurrentLayer(current, _) => Ok(current),
+            _ => Err(anyhow::anyhow!("Keyboard did not report its current layer")),
+        };
+
+        let reported = current()?;
+        if reported == layer {
+            return Ok(());
+        }
+
+        info!(
+            "Keyboard is on layer {} instead of {}, retrying",
+            reported, layer
+        );
+        keyboard.send_message(Operation::ChangeLayer(layer))?;
+
+        match current()? {
+            reported if reported == layer => Ok(()),
+            reported => Err(anyhow::anyhow!(
+                "Keyboard stayed on layer {} instead of changing to {}",
+                reported,
+                layer
+            )),
+        }
+    }
+
+    async fn watch_layer(&self, interval_ms: u64) -> Result<(), anyhow::Error> {
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(interval_ms));
+        let mut keyboard = None;
+        let mut last = None;
+
+        loop {
+            interval.tick().await;
+
+            if keyboard.is_none() {
+                keyboard = self
+                    .connect_to_keyboard()
+                    .map_err(|e| debug!("{:?}", e))
+                    .ok();
+            }
+            let Some(ref connected) = keyboard else {
+                continue;
+            };
+
+            match connected.send_message(Operation::GetLayer) {
+                Ok(KeyboardResponse::CurrentLayer(layer, name)) => {
+                    if last.as_ref() != Some(&layer) {
+                        self.output(
+                            json!({ "layer": layer, "name": name }),
+                            format_args!("⌨: {}", name),
+                        );
+                        last = Some(layer);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // drop the connection, so the next tick reconnects after a replug
+                    debug!("Lost the keyboard: {:?}", e);
+                    keyboard = None;
+                }
+            }
+        }
+    }
+
+    async fn wait_for_layer(
+        &self,
+        layer: u8,
+        timeout_secs: u64,
+        interval_ms: u64,
+    ) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(interval_ms));
+
+        let reached = async {
+            loop {
+                interval.tick().await;
+
+                if let KeyboardResponse::CurrentLayer(current, _) =
+                    keyboard.send_message(Operation::GetLayer)?
+                {
+                    if current == layer {
+                        return Ok::<_, anyhow::Error>(());
+                    }
+                }
+            }
+        };
+
+        tokio::time::timeout(std::time::Duration::from_secs(timeout_secs), reached)
+            .await
+            .map_err(|_| anyhow::anyhow!("Layer {} not active after {}s", layer, timeout_secs))?
+    }
+
+    async fn pin_layer(&self, layer: u8, interval_secs: u64) -> Result<(), anyhow::Error> {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+        let mut keyboard = None;
+
+        loop {
+            interval.tick().await;
+
+            if keyboard.is_none() {
+                keyboard = self
+                    .connect_to_keyboard()
+                    .map_err(|e| debug!("{:?}", e))
+                    .ok();
+            }
+            let Some(ref connected) = keyboard else {
+                continue;
+            };
+
+            let pinned = connected
+                .send_message(Operation::GetLayer)
+                .and_then(|response| {
+                    if let KeyboardResponse::CurrentLayer(current, name) = response {
+                        if current != layer {
+                            info!(
+                                "Layer changed to {} ({}), pinning back to {}",
+                                current, name, layer
+                            );
+                        }
+                    }
+                    connected.send_message(Operation::ChangeLayer(layer))
+                });
+            if let Err(e) = pinned {
+                // drop the connection, so the next tick reconnects after a replug
+                debug!("Lost the keyboard: {:?}", e);
+                keyboard = None;
+            }
+        }
+    }
+
+    async fn replay(&self, file: &std::path::Path, delay_ms: u64) -> Result<(), anyhow::Error> {
+        let contents = std::fs::read_to_string(file)
+            .with_context(|| format!("Could not read replay file {:?}", file))?;
+        // parse everything up front, so a typo doesn't stop a replay halfway through
+        let layers = contents
+            .lines()
+            .enumerate()
+            .map(|(number, line)| (number + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(number, line)| {
+                maybe_hex::<u8>(line)
+                    .map_err(|e| anyhow::anyhow!("Invalid layer on line {}: {}", number, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let keyboard = self.connect_to_keyboard()?;
+        let delay = std::time::Duration::from_millis(delay_ms);
+        let started = std::time::Instant::now();
+
+        for (index, layer) in layers.iter().enumerate() {
+            if index > 0 && !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            keyboard.send_message(Operation::ChangeLayer(*layer))?;
+        }
+
+        let elapsed = started.elapsed();
+        self.output(
+            json!({ "changes": layers.len(), "elapsed_ms": elapsed.as_millis() }),
+            format_args!("Applied {} layer changes in {:?}", layers.len(), elapsed),
+        );
+
+        Ok(())
+    }
+
+    fn tap_layer(&self, layer: u8, hold_ms: u16) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        keyboard.send_message(Operation::TapLayer(layer, hold_ms))?;
+
+        Ok(())
+    }
+
+    /// Sends a set or get jiggler interval `operation` and prints the interval it answers with
+    fn jiggler_interval(&self, operation: Operation) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        match keyboard.send_message(operation)? {
+            KeyboardResponse::JigglerInterval(interval_ms) => self.output(
+                json!({ "jiggler_interval_ms": interval_ms }),
+                format_args!("Jiggler interval: {}ms", interval_ms),
+            ),
+            response => return Err(anyhow::anyhow!("unexpected response {:?}", response)),
+        }
+
+        Ok(())
+    }
+
+    async fn keep_jiggle(&self, interval_secs: u64) -> Result<(), anyhow::Error> {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+        let mut keyboard = None;
+
+        loop {
+            interval.tick().await;
+
+            if keyboard.is_none() {
+                keyboard = self
+                    .connect_to_keyboard()
+                    .map_err(|e| debug!("{:?}", e))
+                    .ok();
+            }
+            let Some(ref connected) = keyboard else {
+                continue;
+            };
+
+            let kept = match connected.send_message(Operation::GetJiggler) {
+                Ok(KeyboardResponse::JigglerStatus(true)) => Ok(()),
+                Ok(_) => {
+                    info!("Jiggler is off, turning it back on");
+                    connected
+                        .send_message(Operation::SetJiggler(true))
+                        .map(|_| ())
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = kept {
+                // drop the connection, so the next tick reconnects after a replug
+                debug!("Lost the keyboard: {:?}", e);
+                keyboard = None;
+            }
+        }
+    }
+
+    async fn momentary_layer(&self, layer: u8, duration_ms: u64) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        let KeyboardResponse::CurrentLayer(previous, _) =
+            keyboard.send_message(Operation::GetLayer)?
+        else {
+            return Err(anyhow::anyhow!("Keyboard did not report its current layer"));
+        };
+
+        keyboard.send_message(Operation::ChangeLayer(layer))?;
+        let _restore = RestoreLayer {
+            keyboard: &keyboard,
+            layer: previous,
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_millis(duration_ms)) => {}
+            _ = tokio::signal::ctrl_c() => debug!("Interrupted, restoring layer early"),
+        }
+
+        Ok(())
+    }
+
+    fn print_devices(&self, devices: Vec<keyboard::DeviceSummary>) -> Result<(), anyhow::Error> {
+        if self.json {
+            println!("{}", serde_json::to_string(&devices)?);
+            return Ok(());
+        }
+
+        for device in devices {
+            println!(
+                "{:04x}:{:04x} usage_page=0x{:04x} usage=0x{:02x} interface={} {} {}",
+                device.vendor_id,
+                device.product_id,
+                device.usage_page,
+                device.usage,
+                device.interface_number,
+                device.manufacturer.unwrap_or_default(),
+                device.product.unwrap_or_default(),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn test_match(
+        &self,
+        config_file: &str,
+        trigger: config::Trigger,
+        target: &config::FocusTarget,
+    ) -> Result<(), anyhow::Error> {
+        let config = config::I3WatcherConfig::load_config(config_file, self.config_format, true)?;
+
+        if config.is_always_excluded(target) {
+            self.output(
+                json!({ "entry": null, "layer": null, "always_excluded": true }),
+                "always excluded -> no layer change",
+            );
+            return Ok(());
+        }
+
+        let entry = config.matches(trigger, target);
+        let layer = match entry {
+            Some(entry) => entry.to_layer,
+            // the fallback layer only applies to windows
+            None if trigger == config::Trigger::Window => config.fallback_layer,
+            None => None,
+        };
+
+        let text = match (entry, layer) {
+            (Some(entry), Some(layer)) => format!("{} -> layer {}", entry.name, layer),
+            (Some(entry), None) => format!("{} -> no layer change", entry.name),
+            (None, Some(layer)) => format!("none -> fallback layer {}", layer),
+            (None, None) => "none".to_string(),
+        };
+        self.output(
+            json!({ "entry": entry.map(|entry| &entry.name), "layer": layer }),
+            text,
+        );
+
+        Ok(())
+    }
+
+    fn snapshot(&self, name: &str) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        let KeyboardResponse::CurrentLayer(layer, _) =
+            keyboard.send_message(Operation::GetLayer)?
+        else {
+            return Err(anyhow::anyhow!("Keyboard did not report its current layer"));
+        };
+        // settings the firmware doesn't support are left out of the profile
+        let color = match query_supported(&keyboard, Operation::GetRgbColor)? {
+            Some(KeyboardResponse::RgbColor(red, green, blue)) => {
+                Some(config::RgbColor(red, green, blue))
+            }
+            _ => None,
+        };
+        let jiggler = match query_supported(&keyboard, Operation::GetJiggler)? {
+            Some(KeyboardResponse::JigglerStatus(enabled)) => Some(enabled),
+            _ => None,
+        };
+        let profile = config::Profile {
+            layer: Some(layer),
+            color,
+            jiggler,
+        };
+
+        if self.json {
+            println!("{}", json!({ "name": name, "profile": profile }));
+        } else {
+            print!("{}", profile.into_toml(name)?);
+        }
+
+        Ok(())
+    }
+
+    fn apply_profile(&self, config_file: &str, name: &str) -> Result<(), anyhow::Error> {
+        let profile = config::load_profile(config_file, name)?;
+        let keyboard = self.connect_to_keyboard()?;
+
+        if let Some(layer) = profile.layer {
+            keyboard.send_message(Operation::ChangeLayer(layer))?;
+        }
+        if let Some(config::RgbColor(red, green, blue)) = profile.color {
+            keyboard.send_message(Operation::SetRgbColor(red, green, blue))?;
+        }
+        if let Some(enabled) = profile.jiggler {
+            keyboard.send_message(Operation::SetJiggler(enabled))?;
+        }
+        info!("Applied profile {}", name);
+
+        Ok(())
+    }
+
+    fn show_report(&self, name: OperationName, args: &[String]) -> Result<(), anyhow::Error> {
+        let reports = name.operation(args)?.reports(&self.opcodes)?;
+
+        let text = reports
+            .iter()
+            .map(|report| {
+                report
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+        self.output(json!(reports), text.join("\n"));
+
+        Ok(())
+    }
+
+    fn monitor(&self) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+        info!("Monitoring reports, press Ctrl-C to stop");
+
+        loop {
+            let Some(report) = keyboard.read_once()? else {
+                continue;
+            };
+
+            let hex = report
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let text = match KeyboardResponse::parse_response(report, &self.opcodes) {
+                KeyboardResponse::None => hex,
+                response => format!("{} {:?}", hex, response),
+            };
+            self.output(json!(report), text);
+        }
+    }
+
+    fn print_via_value(&self, id: u8) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        if let KeyboardResponse::ViaValue(_, value) =
+            keyboard.send_message(Operation::ViaGetValue(id))?
+        {
+            let hex = value
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>();
+            self.output(json!({ "id": id, "value": value }), hex);
+        }
+
+        Ok(())
+    }
+
+    fn layer_count(keyboard: &Keyboard) -> Result<u8, anyhow::Error> {
+        match keyboard.send_message(Operation::GetLayerCount)? {
+            KeyboardResponse::LayerCount(layers) => Ok(layers),
+            _ => Err(anyhow::anyhow!("Keyboard did not report its layer count")),
+        }
+    }
+
+    fn print_layer_count(&self) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        let layers = Self::layer_count(&keyboard)?;
+        self.output(json!({ "layers": layers }), layers);
+
+        Ok(())
+    }
+
+    fn print_battery(&self) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        let response = keyboard.send_message(Operation::GetBattery)?;
+
+        if let KeyboardResponse::Battery(percent) = response {
+            self.output(
+                json!({ "battery": percent }),
+                format_args!("🔋: {}%", percent),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn print_storage(&self) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        match query_supported(&keyboard, Operation::GetStorageInfo)? {
+            Some(KeyboardResponse::StorageInfo { used, total }) => self.output(
+                json!({ "used": used, "total": total }),
+                format_args!("💾: {}/{} bytes", used, total),
+            ),
+            Some(_) => {}
+            None => self.output(json!(null), "💾: unsupported"),
+        }
+
+        Ok(())
+    }
+
+    fn print_status(&self) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        let query = |operation: Operation| query_supported(&keyboard, operation);
+
+        let layer = match query(Operation::GetLayer)? {
+            Some(KeyboardResponse::CurrentLayer(layer, name)) => Some((layer, name)),
+            _ => None,
+        };
+        let color = match query(Operation::GetRgbColor)? {
+            Some(KeyboardResponse::RgbColor(red, green, blue)) => {
+                Some(format!("#{:02x}{:02x}{:02x}", red, green, blue))
+            }
+            _ => None,
+        };
+        let battery = match query(Operation::GetBattery)? {
+            Some(KeyboardResponse::Battery(percent)) => Some(percent),
+            _ => None,
+        };
+        let jiggler = match query(Operation::GetJiggler)? {
+            Some(KeyboardResponse::JigglerStatus(enabled)) => Some(enabled),
+            _ => None,
+        };
+
+        if self.json {
+            let layer = layer.map(|(layer, name)| json!({ "layer": layer, "name": name }));
+            println!(
+                "{}",
+                json!({ "layer": layer, "color": color, "battery": battery, "jiggler": jiggler })
+            );
+            return Ok(());
+        }
+
+        let unsupported = || "unsupported".to_string();
+        println!(
+            "Layer:   {}",
+            layer.map_or_else(unsupported, |(layer, name)| format!("{} ({})", layer, name))
+        );
+        println!("Color:   {}", color.unwrap_or_else(unsupported));
+        println!(
+            "Battery: {}",
+            battery.map_or_else(unsupported, |percent| format!("{}%", percent))
+        );
+        println!(
+            "Jiggler: {}",
+            match jiggler {
+                Some(true) => "on",
+                Some(false) => "off",
+                None => "unsupported",
+            }
+        );
+
+        Ok(())
+    }
+
+    fn print_color(&self) -> Result<(), anyhow::Error> {
         let keyboard = self.connect_to_keyboard()?;
 
-        let response = keyboard.send_message(Operation::ChangeLayer(layer))?;
+        let response = keyboard.send_message(Operation::GetRgbColor)?;
 
-        if let KeyboardResponse::CurrentLayerNum(layer) = response {
-            println!("Current layer: {}", layer);
+        if let KeyboardResponse::RgbColor(red, green, blue) = response {
+            let color = format!("#{:02x}{:02x}{:02x}", red, green, blue);
+            self.output(
+                json!({ "color": color, "red": red, "green": green, "blue": blue }),
+                color,
+            );
         }
 
         Ok(())
     }
 
-    fn keyboard_bootloader(&self) -> Result<(), anyhow::Error> {
+    fn identify(&self) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        let _response = keyboard.send_message(Operation::Identify)?;
+
+        Ok(())
+    }
+
+    fn set_oled(&self, text: &str) -> Result<(), anyhow::Error> {
+        let keyboard = self.connect_to_keyboard()?;
+
+        let _response = keyboard.send_message(Operation::SetOledText(text.to_string()))?;
+
+        Ok(())
+    }
+
+    fn keyboard_bootloader(&self, yes: bool) -> Result<(), anyhow::Error> {
+        if !yes {
+            if !std::io::stdin().is_terminal() {
+                return Err(anyhow::anyhow!(
+                    "Refusing to enter the bootloader without confirmation, pass --yes"
+                ));
+            }
+            let confirmed = inquire::Confirm::new(
+                "Reboot the keyboard into its bootloader? It stops working until it is flashed",
+            )
+            .with_default(false)
+            .prompt()?;
+            if !confirmed {
+                info!("Not entering the bootloader");
+                return Ok(());
+            }
+        }
+
         let keyboard = self.connect_to_keyboard()?;
 
         let _response = keyboard.send_message(Operation::Bootloader)?;
@@ -177,3 +1746,55 @@ impl App {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn target(change: &str, current: u8, layers: Option<u8>) -> u8 {
+        parse_layer_change(change).unwrap().target(current, layers)
+    }
+
+    #[test]
+    fn test_parse_layer_change() {
+        assert!(matches!(
+            parse_layer_change("3"),
+            Ok(LayerChange::Absolute(3))
+        ));
+        assert!(matches!(
+            parse_layer_change("+1"),
+            Ok(LayerChange::Relative(1))
+        ));
+        assert!(matches!(
+            parse_layer_change("-1"),
+            Ok(LayerChange::Relative(-1))
+        ));
+        assert!(parse_layer_change("256").is_err());
+        assert!(parse_layer_change("+x").is_err());
+    }
+
+    #[test]
+    fn test_relative_change_wraps_around_the_layers() {
+        assert_eq!(target("+1", 3, Some(4)), 0);
+        assert_eq!(target("-1", 0, Some(4)), 3);
+        assert_eq!(target("+1", 1, Some(4)), 2);
+        assert_eq!(target("5", 1, Some(4)), 5);
+    }
+
+    #[test]
+    fn test_relative_change_clamps_without_layer_count() {
+        assert_eq!(target("-1", 0, None), 0);
+        assert_eq!(target("+1", 255, None), 255);
+        assert_eq!(target("+1", 2, None), 3);
+        // a layer count of 0 can't be wrapped around either
+        assert_eq!(target("-1", 0, Some(0)), 0);
+    }
+
+    #[test]
+    fn test_relative_change_near_i16_max() {
+        assert_eq!(target("+32767", 255, None), 255);
+        // (255 + 32767) % 4
+        assert_eq!(target("+32767", 255, Some(4)), 2);
+        assert_eq!(target("-32768", 0, Some(4)), 0);
+    }
+}
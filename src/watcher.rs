@@ -0,0 +1,845 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use log::{debug, error};
+use serde_json::json;
+use tokio::task::JoinHandle;
+
+use crate::config::{FocusTarget, I3WatcherConfig, I3WatcherEntry, Trigger};
+use crate::keyboard::{Keyboard, KeyboardResponse, KeyboardWorker, Operation};
+
+/// Applies the configured layer changes as focus moves between windows and workspaces
+pub struct FocusTracker {
+    changer: LayerChanger,
+    config: RwLock<I3WatcherConfig>,
+    pending_restore: Mutex<Option<JoinHandle<()>>>,
+    /// Layer active before focus entered the matched entries, restored on leaving them
+    pre_match: Arc<Mutex<PreMatchLayer>>,
+    /// Jiggler state last sent, so `jiggler_on_match` only sends changes
+    jiggler: Mutex<Option<bool>>,
+    /// Focus from before an always excluded target took it, per trigger. Stands in for the
+    /// excluded target as the previous focus, so e.g. unlocking restores like nothing happened
+    excluded_from: Mutex<HashMap<Trigger, Option<FocusTarget>>>,
+    /// How to print what every focus change matched, if at all
+    report: Option<MatchReport>,
+    /// Leave the keyboard alone, only reporting matches
+    dry_run: bool,
+}
+
+/// How a `FocusTracker` prints the entry each focus change matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchReport {
+    /// Lines like `window="kitty - vim" matched=editor -> layer 2`, for tuning configs
+    Text,
+    /// One JSON object per focus change, for other programs to consume as a stream
+    JsonLines,
+}
+
+/// The layer captured before the first layer change of a visit to the matched entries
+#[derive(Debug, Default)]
+struct PreMatchLayer {
+    /// Set as soon as the capture is queued, so a second change can't capture its own layer
+    captured: bool,
+    /// Filled in once the keyboard answered
+    layer: Option<u8>,
+}
+
+/// What a focus change does to the keyboard. `plan` decides these apart from sending anything,
+/// so the decisions can be tested without a keyboard
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    /// Drop the pending restore, leaving the keyboard on the matched layer
+    CancelRestore,
+    /// Remember the current layer, to restore once focus leaves the matched entries
+    CaptureLayer,
+    ChangeLayer(u8),
+    /// Go back to the layer from before matching, after `grace_ms` when set. Forgets the
+    /// captured layer once sent
+    RestoreLayer {
+        layer: u8,
+        grace_ms: Option<u64>,
+    },
+    SetJiggler(bool),
+    Notify(Vec<String>),
+}
+
+/// What `plan` needs to know about the focus changes before
+#[derive(Debug, Default, Clone, Copy)]
+struct PlanState {
+    /// Whether a layer was captured since the last restore
+    captured: bool,
+    /// The captured layer, once the keyboard reported it
+    previous_layer: Option<u8>,
+    /// Whether a restore is waiting out the exit grace period
+    restore_pending: bool,
+    /// Jiggler state last sent to the keyboard
+    jiggler: Option<bool>,
+}
+
+/// Decides what moving focus from `prev_target` to `target` does to the keyboard
+fn plan(
+    config: &I3WatcherConfig,
+    trigger: Trigger,
+    prev_target: Option<&FocusTarget>,
+    target: &FocusTarget,
+    state: PlanState,
+) -> Vec<Action> {
+    if config.is_always_excluded(target) {
+        debug!(
+            "win: {:?} is always excluded, leaving the keyboard alone",
+            target.name
+        );
+        return Vec::new();
+    }
+
+    let prev_entry = prev_target.and_then(|prev_target| config.matches(trigger, prev_target));
+    // leaving matched entries goes back to the base layer, or else to the captured one
+    let restore_layer = |entry: &I3WatcherEntry| entry.base_layer.or(state.previous_layer);
+    let mut actions = Vec::new();
+
+    let matched = config.matches(trigger, target);
+    if config.jiggler_on_match && trigger == Trigger::Window {
+        let media = matched.is_some_and(|entry| entry.media);
+        if state.jiggler != Some(media) {
+            actions.push(Action::SetJiggler(media));
+        }
+    }
+
+    let Some(entry) = matched else {
+        if let Some(enabled) = prev_entry
+            .and_then(|entry| entry.base_jiggler)
+            .filter(|_| !config.jiggler_on_match)
+        {
+            actions.push(Action::SetJiggler(enabled));
+        }
+        if let Some(layer) = prev_entry.and_then(|entry| {
+            debug!("win: exited matching window: {:?}", entry);
+            restore_layer(entry)
+        }) {
+            actions.push(Action::RestoreLayer {
+                layer,
+                grace_ms: config.exit_grace_ms,
+            });
+        } else if let (Trigger::Window, Some(layer)) = (trigger, config.fallback_layer) {
+            debug!("win: no entry matched, using fallback layer {}", layer);
+            actions.push(Action::ChangeLayer(layer));
+        }
+        return actions;
+    };
+
+    if let Some(prev_target) = prev_target.filter(|prev_target| {
+        prev_entry.is_some_and(|prev_entry| std::ptr::eq(prev_entry, entry))
+            && entry.ignores_change(prev_target, target)
+    }) {
+        debug!("win: ignoring title change from {:?}", prev_target.name);
+        return actions;
+    }
+    debug!("win: matched window: {:?}", entry);
+    if state.restore_pending {
+        actions.push(Action::CancelRestore);
+    }
+    if let Some(command) = entry.notify_command(target) {
+        actions.push(Action::Notify(command));
+    }
+    let left_entry = prev_entry.filter(|prev_entry| !std::ptr::eq(*prev_entry, entry));
+    if let Some(enabled) = entry
+        .enable_jiggler
+        .or_else(|| left_entry.and_then(|prev_entry| prev_entry.base_jiggler))
+        .filter(|_| !config.jiggler_on_match)
+    {
+        actions.push(Action::SetJiggler(enabled));
+    }
+    if let Some(layer) = entry.layer_for(target) {
+        // a cancelled restore leaves the keyboard on a matched layer, keep the one captured
+        if !state.captured && !state.restore_pending {
+            actions.push(Action::CaptureLayer);
+        }
+        actions.push(Action::ChangeLayer(layer));
+    } else if let Some(layer) = left_entry.and_then(restore_layer) {
+        // The new entry doesn't pick a layer, so leave the previous entry's one
+        debug!("win: switched entries, restoring layer {}", layer);
+        actions.push(Action::RestoreLayer {
+            layer,
+            grace_ms: None,
+        });
+    }
+
+    actions
+}
+
+fn change_layer_job(keyboard: &Keyboard, layer: u8) -> Result<(), anyhow::Error> {
+    keyboard
+        .send_message(Operation::ChangeLayer(layer))
+        .map(|_| ())
+}
+
+/// Prints what `target` matched as `report`, with the layer the match changes to
+fn print_match(
+    report: MatchReport,
+    trigger: Trigger,
+    target: &FocusTarget,
+    entry: Option<&I3WatcherEntry>,
+    layer: Option<u8>,
+) {
+    let kind = match trigger {
+        Trigger::Window => "window",
+        Trigger::Workspace => "workspace",
+    };
+    if report == MatchReport::JsonLines {
+        let mut event = json!({
+            "matched": entry.map(|entry| &entry.name),
+            "layer": layer,
+        });
+        event[kind] = json!(target.name);
+        println!("{}", event);
+        return;
+    }
+
+    let matched = match (entry, layer) {
+        (Some(entry), Some(layer)) => format!("{} -> layer {}", entry.name, layer),
+        (Some(entry), None) => entry.name.clone(),
+        (None, _) => "none".to_string(),
+    };
+    println!("{}={:?} matched={}", kind, target.name, matched);
+}
+
+/// Runs an entry's notify command in the background, logging when it fails
+fn spawn_notify(command: Vec<String>) {
+    debug!("win: running notify command {:?}", command);
+    match tokio::process::Command::new(&command[0])
+        .args(&command[1..])
+        .spawn()
+    {
+        Ok(mut child) => {
+            tokio::spawn(async move {
+                match child.wait().await {
+                    Ok(status) if !status.success() => {
+                        error!("Notify command {:?} exited with {}", command, status)
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Error: {:?}", e),
+                }
+            });
+        }
+        Err(e) => error!("Could not run notify command {:?}: {:?}", command, e),
+    }
+}
+
+/// Sends layer changes to the keyboard, holding back any that follows the previous one by less
+/// than `min_interval`. A held back change is sent once the interval elapses, unless a newer one
+/// replaces it first
+#[derive(Clone)]
+struct LayerChanger {
+    worker: KeyboardWorker,
+    min_interval: Option<Duration>,
+    state: Arc<Mutex<LayerChangerState>>,
+}
+
+#[derive(Default)]
+struct LayerChangerState {
+    last_change: Option<Instant>,
+    deferred: Option<JoinHandle<()>>,
+    /// Last layer sent to the keyboard, to send again once it reconnects
+    last_layer: Option<u8>,
+}
+
+impl LayerChanger {
+    fn change(&self, layer: u8) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(deferred) = state.deferred.take() {
+            deferred.abort();
+        }
+
+        let wait =
+            self.min_interval
+                .zip(state.last_change)
+                .and_then(|(min_interval, last_change)| {
+                    min_interval.checked_sub(last_change.elapsed())
+                });
+
+        let Some(wait) = wait else {
+            state.last_change = Some(Instant::now());
+            state.last_layer = Some(layer);
+            return self
+                .worker
+                .submit(move |keyboard| change_layer_job(keyboard, layer));
+        };
+
+        debug!("win: holding back layer {} for {:?}", layer, wait);
+        let changer = self.clone();
+        state.deferred = Some(tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            if let Err(e) = changer.change(layer) {
+                error!("Error: {:?}", e);
+            }
+        }));
+
+        Ok(())
+    }
+
+    /// Changes to `layer` like `change`, but waits out the minimum interval instead of holding
+    /// the change back, and returns once the keyboard answered it
+    async fn change_and_wait(&self, layer: u8) -> Result<(), anyhow::Error> {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                if let Some(deferred) = state.deferred.take() {
+                    deferred.abort();
+                }
+                let wait = self.min_interval.zip(state.last_change).and_then(
+                    |(min_interval, last_change)| min_interval.checked_sub(last_change.elapsed()),
+                );
+                if wait.is_none() {
+                    state.last_change = Some(Instant::now());
+                    state.last_layer = Some(layer);
+                }
+                wait
+            };
+
+            // another change may go out while waiting, so the interval is checked again after
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => break,
+            }
+        }
+
+        self.worker
+            .request(Operation::ChangeLayer(layer))
+            .await
+            .map(|_| ())
+    }
+}
+
+impl FocusTracker {
+    pub fn new(
+        worker: KeyboardWorker,
+        config: I3WatcherConfig,
+        report: Option<MatchReport>,
+        dry_run: bool,
+    ) -> Self {
+        Self {
+            changer: LayerChanger {
+                worker,
+                min_interval: config.min_layer_interval_ms.map(Duration::from_millis),
+                state: Default::default(),
+            },
+            config: RwLock::new(config),
+            pending_restore: Mutex::new(None),
+            pre_match: Default::default(),
+            jiggler: Mutex::new(None),
+            excluded_from: Default::default(),
+            report,
+            dry_run,
+        }
+    }
+
+    pub fn focus_changed(
+        &self,
+        trigger: Trigger,
+        prev_target: Option<&FocusTarget>,
+        target: &FocusTarget,
+    ) -> Result<(), anyhow::Error> {
+        let config = self.config.read().unwrap();
+        if let Some(report) = self.report {
+            let entry = config.matches(trigger, target);
+            let layer = match entry {
+                Some(entry) => entry.layer_for(target),
+                None if trigger == Trigger::Window && !config.is_always_excluded(target) => {
+                    config.fallback_layer
+                }
+                None => None,
+            };
+            print_match(report, trigger, target, entry, layer);
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+
+        let mut excluded_from = self.excluded_from.lock().unwrap();
+        let substitute;
+        let prev_target = match prev_target {
+            Some(prev_target) if config.is_always_excluded(prev_target) => {
+                substitute = excluded_from.get(&trigger).cloned().flatten();
+                substitute.as_ref()
+            }
+            prev_target => prev_target,
+        };
+        if config.is_always_excluded(target) {
+            excluded_from.insert(trigger, prev_target.cloned());
+        } else {
+            excluded_from.remove(&trigger);
+        }
+        drop(excluded_from);
+
+        let state = {
+            let pre_match = self.pre_match.lock().unwrap();
+            PlanState {
+                captured: pre_match.captured,
+                previous_layer: pre_match.layer,
+                restore_pending: self
+                    .pending_restore
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .is_some_and(|pending| !pending.is_finished()),
+                jiggler: *self.jiggler.lock().unwrap(),
+            }
+        };
+
+        for action in plan(&config, trigger, prev_target, target, state) {
+            match action {
+                Action::CancelRestore => self.cancel_pending_restore(),
+                Action::CaptureLayer => self.capture_previous_layer()?,
+                Action::ChangeLayer(layer) => self.changer.change(layer)?,
+                Action::RestoreLayer { layer, grace_ms } => self.restore_layer(layer, grace_ms)?,
+                Action::SetJiggler(enabled) => self.set_jiggler(enabled)?,
+                Action::Notify(command) => spawn_notify(command),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Changes back to the fallback layer, or layer 0 without one, waiting for the change to
+    /// land so it is safe to exit right after
+    pub async fn reset_layer(&self) -> Result<(), anyhow::Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let layer = self.config.read().unwrap().fallback_layer.unwrap_or(0);
+        debug!("win: resetting to layer {}", layer);
+
+        self.changer
+            .worker
+            .request(Operation::ChangeLayer(layer))
+            .await
+            .map(|_| ())
+    }
+
+    /// Checks the keyboard still answers, so a disconnect shows up without waiting for a focus
+    /// change
+    pub async fn heartbeat(&self) -> Result<(), anyhow::Error> {
+        self.changer
+            .worker
+            .request(Operation::GetLayer)
+            .await
+            .map(|_| ())
+    }
+
+    /// Sends the last layer change again, for a keyboard that was reconnected and lost it
+    pub fn resend_layer(&self) -> Result<(), anyhow::Error> {
+        let last_layer = self.changer.state.lock().unwrap().last_layer;
+        match last_layer {
+            Some(layer) => {
+                debug!("win: resending layer {}", layer);
+                self.changer.change(layer)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Changes to `layer` for the control socket, through the same minimum interval as focus
+    /// changes and remembered for `resend_layer`, returning once the keyboard answered it
+    pub async fn change_layer(&self, layer: u8) -> Result<(), anyhow::Error> {
+        debug!("win: changing to layer {} on request", layer);
+        self.changer.change_and_wait(layer).await
+    }
+
+    /// Reads the keyboard's current layer number and name
+    pub async fn current_layer(&self) -> Result<(u8, String), anyhow::Error> {
+        match self.changer.worker.request(Operation::GetLayer).await? {
+            KeyboardResponse::CurrentLayer(layer, name) => Ok((layer, name)),
+            response => Err(anyhow!("unexpected response {:?}", response)),
+        }
+    }
+
+    /// Turns the jiggler on or off, or toggles it from what the keyboard reports with `None`,
+    /// and returns whether it is on now
+    pub async fn change_jiggler(&self, enabled: Option<bool>) -> Result<bool, anyhow::Error> {
+        let worker = &self.changer.worker;
+        let enabled = match enabled {
+            Some(enabled) => enabled,
+            None => match worker.request(Operation::GetJiggler).await? {
+                KeyboardResponse::JigglerStatus(enabled) => !enabled,
+                response => return Err(anyhow!("unexpected response {:?}", response)),
+            },
+        };
+
+        match worker.request(Operation::SetJiggler(enabled)).await? {
+            KeyboardResponse::JigglerStatus(enabled) => {
+                *self.jiggler.lock().unwrap() = Some(enabled);
+                Ok(enabled)
+            }
+            response => Err(anyhow!("unexpected response {:?}", response)),
+        }
+    }
+
+    /// Swaps in a reloaded config. The minimum layer interval and protocol are set up once at
+    /// startup, so changes to those only apply after a restart
+    pub fn reload(&self, config: I3WatcherConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Restores `layer` once the exit grace period elapses, unless a matching window is
+    /// focused again in the meantime. The captured layer is forgotten once restored, so the
+    /// next visit to the matched entries captures afresh
+    fn restore_layer(&self, layer: u8, grace_ms: Option<u64>) -> Result<(), anyhow::Error> {
+        let Some(grace_ms) = grace_ms else {
+            *self.pre_match.lock().unwrap() = PreMatchLayer::default();
+            return self.changer.change(layer);
+        };
+
+        let changer = self.changer.clone();
+        let pre_match = self.pre_match.clone();
+        let restore = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(grace_ms)).await;
+            debug!("win: grace period elapsed, restoring layer {}", layer);
+            *pre_match.lock().unwrap() = PreMatchLayer::default();
+            if let Err(e) = changer.change(layer) {
+                error!("Error: {:?}", e);
+            }
+        });
+
+        if let Some(previous) = self.pending_restore.lock().unwrap().replace(restore) {
+            previous.abort();
+        }
+
+        Ok(())
+    }
+
+    /// Remembers the current layer before a layer change, so leaving the entry can restore it.
+    /// Jobs run in order, so this reads the layer before the change lands
+    fn capture_previous_layer(&self) -> Result<(), anyhow::Error> {
+        self.pre_match.lock().unwrap().captured = true;
+        let pre_match = self.pre_match.clone();
+        self.changer.worker.submit(move |keyboard| {
+            if let KeyboardResponse::CurrentLayer(layer, _) =
+                keyboard.send_message(Operation::GetLayer)?
+            {
+                debug!("win: layer before matching was {}", layer);
+                let mut pre_match = pre_match.lock().unwrap();
+                // a restore that already went out leaves nothing to capture for
+                if pre_match.captured {
+                    pre_match.layer = Some(layer);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn set_jiggler(&self, enabled: bool) -> Result<(), anyhow::Error> {
+        debug!(
+            "win: turning the jiggler {}",
+            if enabled { "on" } else { "off" }
+        );
+        *self.jiggler.lock().unwrap() = Some(enabled);
+        self.changer.worker.submit(move |keyboard| {
+            keyboard
+                .send_message(Operation::SetJiggler(enabled))
+                .map(|_| ())
+        })
+    }
+
+    /// Cancels the pending layer restore
+    fn cancel_pending_restore(&self) {
+        if let Some(pending) = self.pending_restore.lock().unwrap().take() {
+            debug!("win: cancelled pending layer restore");
+            pending.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn target(name: &str) -> FocusTarget {
+        FocusTarget {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // the structs keep private caches, so they are built by assigning to a default
+    fn entry(name: &str, to_layer: Option<u8>, base_layer: Option<u8>) -> I3WatcherEntry {
+        let mut entry = I3WatcherEntry::default();
+        entry.name = name.to_string();
+        entry.include = vec![name.to_string()];
+        entry.to_layer = to_layer;
+        entry.base_layer = base_layer;
+        entry
+    }
+
+    fn config(entries: Vec<I3WatcherEntry>) -> I3WatcherConfig {
+        let mut config = I3WatcherConfig::default();
+        config.entries = entries;
+        config
+    }
+
+    /// Plays focus changes against `plan`, acting on its actions like a keyboard would
+    struct Simulation {
+        config: I3WatcherConfig,
+        state: PlanState,
+        layer: u8,
+        focused: Option<FocusTarget>,
+    }
+
+    impl Simulation {
+        fn new(entries: Vec<I3WatcherEntry>, layer: u8) -> Self {
+            Self {
+                config: config(entries),
+                state: PlanState::default(),
+                layer,
+                focused: None,
+            }
+        }
+
+        fn focus(&mut self, name: &str) -> Vec<Action> {
+            let target = target(name);
+            let actions = plan(
+                &self.config,
+                Trigger::Window,
+                self.focused.as_ref(),
+                &target,
+                self.state,
+            );
+            for action in &actions {
+                match action {
+                    Action::CaptureLayer => {
+                        self.state.captured = true;
+                        self.state.previous_layer = Some(self.layer);
+                    }
+                    Action::ChangeLayer(layer) => self.layer = *layer,
+                    Action::RestoreLayer { layer, .. } => {
+                        self.layer = *layer;
+                        self.state = PlanState {
+                            jiggler: self.state.jiggler,
+                            ..Default::default()
+                        };
+                    }
+                    Action::SetJiggler(enabled) => self.state.jiggler = Some(*enabled),
+                    Action::CancelRestore | Action::Notify(_) => {}
+                }
+            }
+            // the tracker passes over always excluded targets the same way
+            if !self.config.is_always_excluded(&target) {
+                self.focused = Some(target);
+            }
+            actions
+        }
+    }
+
+    #[test]
+    fn test_plan_captures_and_restores_previous_layer() {
+        let mut simulation = Simulation::new(vec![entry("mpv", Some(2), None)], 5);
+
+        assert_eq!(
+            simulation.focus("mpv"),
+            [Action::CaptureLayer, Action::ChangeLayer(2)]
+        );
+        assert_eq!(
+            simulation.focus("kitty"),
+            [Action::RestoreLayer {
+                layer: 5,
+                grace_ms: None
+            }]
+        );
+
+        // the captured layer is forgotten, so the next visit captures again
+        simulation.layer = 3;
+        assert_eq!(
+            simulation.focus("mpv"),
+            [Action::CaptureLayer, Action::ChangeLayer(2)]
+        );
+        simulation.focus("kitty");
+        assert_eq!(simulation.layer, 3);
+    }
+
+    #[test]
+    fn test_plan_captures_when_entering_an_entry_with_base_layer() {
+        let mut simulation = Simulation::new(
+            vec![
+                entry("chromium", Some(1), Some(0)),
+                entry("mpv", Some(2), None),
+            ],
+            5,
+        );
+
+        simulation.focus("chromium");
+        assert_eq!(simulation.focus("mpv"), [Action::ChangeLayer(2)]);
+        simulation.focus("kitty");
+
+        assert_eq!(simulation.layer, 5);
+    }
+
+    #[test]
+    fn test_plan_switching_to_entry_without_layer_restores_captured_layer() {
+        let mut simulation = Simulation::new(
+            vec![entry("mpv", Some(2), None), entry("vlc", None, None)],
+            5,
+        );
+
+        simulation.focus("mpv");
+        assert_eq!(
+            simulation.focus("vlc"),
+            [Action::RestoreLayer {
+                layer: 5,
+                grace_ms: None
+            }]
+        );
+    }
+
+    #[test]
+    fn test_plan_fallback_and_grace() {
+        let mut simulation = Simulation::new(vec![entry("mpv", Some(2), Some(0))], 5);
+        simulation.config.fallback_layer = Some(1);
+        simulation.config.exit_grace_ms = Some(300);
+
+        assert_eq!(simulation.focus("kitty"), [Action::ChangeLayer(1)]);
+        simulation.focus("mpv");
+        assert_eq!(
+            simulation.focus("kitty"),
+            [Action::RestoreLayer {
+                layer: 0,
+                grace_ms: Some(300)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_plan_cancels_pending_restore_without_capturing() {
+        let config = config(vec![entry("mpv", Some(2), None)]);
+        let state = PlanState {
+            captured: true,
+            previous_layer: Some(5),
+            restore_pending: true,
+            jiggler: None,
+        };
+
+        assert_eq!(
+            plan(
+                &config,
+                Trigger::Window,
+                Some(&target("kitty")),
+                &target("mpv"),
+                state
+            ),
+            [Action::CancelRestore, Action::ChangeLayer(2)]
+        );
+    }
+
+    #[test]
+    fn test_plan_entry_jiggler() {
+        let mut mpv = entry("mpv", Some(2), None);
+        mpv.enable_jiggler = Some(true);
+        mpv.base_jiggler = Some(false);
+        let mut simulation = Simulation::new(vec![mpv, entry("vlc", None, None)], 0);
+
+        assert_eq!(
+            simulation.focus("mpv"),
+            [
+                Action::SetJiggler(true),
+                Action::CaptureLayer,
+                Action::ChangeLayer(2)
+            ]
+        );
+        assert_eq!(
+            simulation.focus("vlc"),
+            [
+                Action::SetJiggler(false),
+                Action::RestoreLayer {
+                    layer: 0,
+                    grace_ms: None
+                }
+            ]
+        );
+        simulation.focus("mpv");
+        assert_eq!(
+            simulation.focus("kitty"),
+            [
+                Action::SetJiggler(false),
+                Action::RestoreLayer {
+                    layer: 0,
+                    grace_ms: None
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_jiggler_on_match() {
+        let mut mpv = entry("mpv", None, None);
+        mpv.media = true;
+        // entry settings give way to the global mode
+        mpv.base_jiggler = Some(true);
+        let mut simulation = Simulation::new(vec![mpv, entry("vlc", None, None)], 0);
+        simulation.config.jiggler_on_match = true;
+
+        assert_eq!(simulation.focus("kitty"), [Action::SetJiggler(false)]);
+        assert_eq!(simulation.focus("firefox"), []);
+        assert_eq!(simulation.focus("mpv"), [Action::SetJiggler(true)]);
+        assert_eq!(simulation.focus("mpv - paused"), []);
+        assert_eq!(simulation.focus("vlc"), [Action::SetJiggler(false)]);
+    }
+
+    #[test]
+    fn test_plan_ignores_title_changes() {
+        let mut mpv = entry("mpv", Some(2), None);
+        mpv.ignore_title_changes = true;
+        mpv.match_field = crate::config::MatchField::Class;
+        let config = config(vec![mpv]);
+        let playing = |name: &str| FocusTarget {
+            class: Some("mpv".to_string()),
+            ..target(name)
+        };
+
+        assert_eq!(
+            plan(
+                &config,
+                Trigger::Window,
+                Some(&playing("movie.mkv - 10%")),
+                &playing("movie.mkv - 11%"),
+                PlanState::default()
+            ),
+            []
+        );
+    }
+
+    #[test]
+    fn test_plan_passes_over_always_excluded_targets() {
+        // the exclusion patterns are private to the config, so this one goes through a file
+        let path = std::env::temp_dir().join(format!("dactyl-exclude-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[global]\nfallback_layer = 0\nalways_exclude = \"i3lock\"\n\n\
+             [entries.mpv]\ninclude = \"mpv\"\nto_layer = 2\n",
+        )
+        .unwrap();
+        let config = I3WatcherConfig::load_config(
+            path.to_str().unwrap(),
+            crate::config::ConfigFormat::Toml,
+            true,
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let mut simulation = Simulation::new(Vec::new(), 5);
+        simulation.config = config.unwrap();
+
+        // neither the fallback nor a restore, and leaving the lock restores like it never
+        // had focus
+        assert_eq!(simulation.focus("i3lock"), []);
+        simulation.focus("mpv");
+        assert_eq!(simulation.focus("i3lock"), []);
+        assert_eq!(simulation.focus("mpv"), [Action::ChangeLayer(2)]);
+        simulation.focus("i3lock");
+        assert_eq!(
+            simulation.focus("kitty"),
+            [Action::RestoreLayer {
+                layer: 5,
+                grace_ms: None
+            }]
+        );
+    }
+}
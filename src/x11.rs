@@ -0,0 +1,157 @@
+use anyhow::Context;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{Atom, AtomEnum, ConnectionExt, GetPropertyReply, Window};
+use x11rb::rust_connection::RustConnection;
+
+use crate::config::FocusTarget;
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        _NET_ACTIVE_WINDOW,
+        _NET_CURRENT_DESKTOP,
+        _NET_DESKTOP_NAMES,
+        _NET_WM_NAME,
+        _NET_WM_STATE,
+        _NET_WM_STATE_FULLSCREEN,
+        UTF8_STRING,
+    }
+}
+
+/// Reads the focused window from the EWMH properties an X11 window manager keeps on the root
+/// window, for window managers other than i3
+pub struct X11 {
+    connection: RustConnection,
+    root: Window,
+    atoms: Atoms,
+}
+
+impl X11 {
+    /// Connects to the display in `$DISPLAY`
+    pub fn connect() -> Result<Self, anyhow::Error> {
+        let (connection, screen) =
+            x11rb::connect(None).context("Could not connect to the X11 display")?;
+        let root = connection.setup().roots[screen].root;
+        let atoms = Atoms::new(&connection)?.reply()?;
+
+        Ok(Self {
+            connection,
+            root,
+            atoms,
+        })
+    }
+
+    /// Describes the window in `_NET_ACTIVE_WINDOW`, if any window is focused and has a name
+    pub fn focus_target(&self) -> Result<Option<FocusTarget>, anyhow::Error> {
+        let active = self
+            .number(
+                self.root,
+                self.atoms._NET_ACTIVE_WINDOW,
+                AtomEnum::WINDOW.into(),
+            )?
+            .filter(|&window| window != x11rb::NONE);
+        let Some(window) = active else {
+            return Ok(None);
+        };
+
+        // `_NET_WM_NAME` is UTF-8, falling back to the older `WM_NAME` for clients without it
+        let name = match self.string(window, self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING)? {
+            Some(name) => name,
+            None => match self.string(window, AtomEnum::WM_NAME.into(), AtomEnum::ANY.into())? {
+                Some(name) => name,
+                None => return Ok(None),
+            },
+        };
+        // `WM_CLASS` holds the instance and then the class, each nul terminated
+        let class = self.string(window, AtomEnum::WM_CLASS.into(), AtomEnum::ANY.into())?;
+        let mut class = class.as_deref().unwrap_or_default().split('\0');
+        let instance = class
+            .next()
+            .filter(|s| !s.is_empty())
+            .map(ToOwned::to_owned);
+        let class = class
+            .next()
+            .filter(|s| !s.is_empty())
+            .map(ToOwned::to_owned);
+        let fullscreen = self
+            .property(window, self.atoms._NET_WM_STATE, AtomEnum::ATOM.into())?
+            .and_then(|states| {
+                Some(
+                    states
+                        .value32()?
+                        .any(|state| state == self.atoms._NET_WM_STATE_FULLSCREEN),
+                )
+            });
+
+        Ok(Some(FocusTarget {
+            name,
+            floating: None,
+            fullscreen,
+            class,
+            instance,
+            new_window: false,
+            workspace: self.current_desktop()?,
+        }))
+    }
+
+    /// Name of the current desktop, the closest X11 has to a workspace
+    fn current_desktop(&self) -> Result<Option<String>, anyhow::Error> {
+        let current = self.number(
+            self.root,
+            self.atoms._NET_CURRENT_DESKTOP,
+            AtomEnum::CARDINAL.into(),
+        )?;
+        let Some(current) = current else {
+            return Ok(None);
+        };
+        let names = self.string(
+            self.root,
+            self.atoms._NET_DESKTOP_NAMES,
+            self.atoms.UTF8_STRING,
+        )?;
+
+        Ok(names.and_then(|names| {
+            names
+                .split('\0')
+                .nth(current as usize)
+                .map(ToOwned::to_owned)
+        }))
+    }
+
+    /// First 32 bit value of `property` on `window`, e.g. a window id or a number
+    fn number(
+        &self,
+        window: Window,
+        property: Atom,
+        kind: Atom,
+    ) -> Result<Option<u32>, anyhow::Error> {
+        Ok(self
+            .property(window, property, kind)?
+            .and_then(|reply| reply.value32()?.next()))
+    }
+
+    fn string(
+        &self,
+        window: Window,
+        property: Atom,
+        kind: Atom,
+    ) -> Result<Option<String>, anyhow::Error> {
+        Ok(self
+            .property(window, property, kind)?
+            .map(|reply| String::from_utf8_lossy(&reply.value).into_owned()))
+    }
+
+    /// `property` on `window`, or `None` when it isn't set
+    fn property(
+        &self,
+        window: Window,
+        property: Atom,
+        kind: Atom,
+    ) -> Result<Option<GetPropertyReply>, anyhow::Error> {
+        let reply = self
+            .connection
+            .get_property(false, window, property, kind, 0, u32::MAX)?
+            .reply()?;
+
+        Ok((reply.type_ != x11rb::NONE).then_some(reply))
+    }
+}